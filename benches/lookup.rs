@@ -0,0 +1,93 @@
+// compares the old linear scan over a client's Vec of transactions against the engine's tx id index
+// run with `cargo bench --bench lookup`, the sizes can be changed with BENCH_ROWS and BENCH_LOOKUPS
+use std::env;
+use std::time::{Duration, Instant};
+
+// LOCAL
+use rust_coding_test::{Amount, PaymentsEngine, Transaction, TransactionType};
+
+const CLIENTS: u16 = 4;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn deposit(tx: u32) -> Transaction {
+    Transaction {
+        transaction_type: TransactionType::Deposit,
+        client: (tx % CLIENTS as u32) as u16,
+        tx,
+        amount: Some(Amount::from_units(10_000)),
+        reason: None,
+        to: None,
+    }
+}
+
+fn dispute(tx: u32) -> Transaction {
+    Transaction {
+        transaction_type: TransactionType::Dispute,
+        client: (tx % CLIENTS as u32) as u16,
+        tx,
+        amount: None,
+        reason: None,
+        to: None,
+    }
+}
+
+// disputes are spread evenly over every deposit so the scan has to walk half a client's history on average
+fn disputed_ids(rows: u64, lookups: u64) -> impl Iterator<Item = u32> {
+    let step = (rows / lookups).max(1);
+    (0..lookups).map(move |n| ((n * step) % rows) as u32)
+}
+
+fn per_lookup(elapsed: Duration, lookups: u64) -> f64 {
+    elapsed.as_nanos() as f64 / lookups as f64
+}
+
+// what find_referenced_transaction used to do, one Vec of whole transactions per client
+fn linear_scan(rows: u64, lookups: u64) -> Duration {
+    let mut histories: Vec<Vec<Transaction>> = (0..CLIENTS).map(|_| Vec::new()).collect();
+    for tx in 0..rows as u32 {
+        let deposit = deposit(tx);
+        histories[deposit.client as usize].push(deposit);
+    }
+    let start = Instant::now();
+    let mut found = 0;
+    for tx in disputed_ids(rows, lookups) {
+        let client = (tx % CLIENTS as u32) as usize;
+        if histories[client].iter_mut().any(|t| t.tx == tx && t.amount.is_some()) {
+            found += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(found, lookups);
+    elapsed
+}
+
+fn indexed(rows: u64, lookups: u64) -> (Duration, Duration) {
+    let mut engine = PaymentsEngine::new();
+    let start = Instant::now();
+    for tx in 0..rows as u32 {
+        engine.apply(deposit(tx)).unwrap();
+    }
+    let loading = start.elapsed();
+    let start = Instant::now();
+    for tx in disputed_ids(rows, lookups) {
+        engine.apply(dispute(tx)).unwrap();
+    }
+    (loading, start.elapsed())
+}
+
+fn main() {
+    let rows = env_or("BENCH_ROWS", 3_000_000);
+    // the scan is quadratic, so it only gets a sample of the disputes
+    let scan_lookups = env_or("BENCH_LOOKUPS", 2_000);
+    println!("{} deposits over {} clients", rows, CLIENTS);
+
+    let scan = linear_scan(rows, scan_lookups);
+    println!("linear scan: {} lookups in {:?} ({:.0} ns/lookup)", scan_lookups, scan, per_lookup(scan, scan_lookups));
+
+    let (loading, lookups) = indexed(rows, rows);
+    println!("tx id index: {} deposits applied in {:?}", rows, loading);
+    println!("tx id index: {} disputes in {:?} ({:.0} ns/lookup)", rows, lookups, per_lookup(lookups, rows));
+}
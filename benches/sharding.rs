@@ -0,0 +1,82 @@
+// compares process_transaction_data on one thread against process_parallel on N worker threads over the same generated file
+// run with `cargo bench --bench sharding`, the sizes can be changed with BENCH_ROWS, BENCH_CLIENTS and BENCH_THREADS (e.g. "1,2,4,8")
+// the file is written to the system temp directory (50M rows is about 1.3GB) and removed afterwards, set BENCH_KEEP to reuse it between runs
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// LOCAL
+use rust_coding_test::{PaymentsEngine, RawClient, AccountOrder, FraudLog};
+use rust_coding_test::components::utilities::{process_transaction_data, process_parallel};
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+// mostly deposits and withdrawals spread over every client, with a dispute and resolve every so often
+// written by hand rather than with generate_test_data because that only ever uses 3 clients, which would leave most shards idle
+fn generate(path: &PathBuf, rows: u64, clients: u64) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "type,client,tx,amount")?;
+    for tx in 0..rows {
+        let client = tx % clients;
+        match tx % 10 {
+            7 => writeln!(file, "withdrawal,{},{},1.5", client, tx)?,
+            // the deposit from 8 rows back, so the same client has to own it
+            8 => writeln!(file, "dispute,{},{},", (tx - 8) % clients, tx - 8)?,
+            9 => writeln!(file, "resolve,{},{},", (tx - 9) % clients, tx - 9)?,
+            _ => writeln!(file, "deposit,{},{},2.0001", client, tx)?,
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn per_second(rows: u64, elapsed: Duration) -> f64 {
+    rows as f64 / elapsed.as_secs_f64()
+}
+
+fn accounts(engine: &PaymentsEngine) -> Vec<RawClient> {
+    engine.sorted_accounts(AccountOrder::ClientId).into_iter().map(RawClient::from).collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let rows = env_or("BENCH_ROWS", 50_000_000);
+    let clients = env_or("BENCH_CLIENTS", 1_000).clamp(1, u16::MAX as u64 + 1);
+    let threads: Vec<usize> = env::var("BENCH_THREADS").unwrap_or_else(|_| "1,2,4,8".to_string())
+        .split(',')
+        .filter_map(|threads| threads.trim().parse().ok())
+        .collect();
+    let path = env::temp_dir().join(format!("sharding_bench_{}_{}.csv", rows, clients));
+    if !path.exists() {
+        let start = Instant::now();
+        generate(&path, rows, clients)?;
+        println!("generated {} rows over {} clients in {:?}", rows, clients, start.elapsed());
+    }
+    let input: OsString = path.clone().into_os_string();
+    println!("{} rows over {} clients from {}", rows, clients, path.display());
+
+    let start = Instant::now();
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&input, &mut engine, &mut ())?;
+    let single = start.elapsed();
+    println!("single thread: {:?} ({:.0} rows/s)", single, per_second(rows, single));
+
+    let expected = accounts(&engine);
+    drop(engine);
+    for threads in threads {
+        let start = Instant::now();
+        let engine = process_parallel(std::slice::from_ref(&input), None, PaymentsEngine::new(), threads, &mut FraudLog::new(io::sink()))?;
+        let elapsed = start.elapsed();
+        assert!(accounts(&engine) == expected, "{} threads came up with different accounts", threads);
+        println!("{} threads: {:?} ({:.0} rows/s, {:.2}x)", threads, elapsed, per_second(rows, elapsed), single.as_secs_f64() / elapsed.as_secs_f64());
+    }
+    if env::var("BENCH_KEEP").is_err() {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
@@ -1,5 +1,8 @@
 pub mod data_structures;
 pub mod implementations;
 pub mod utilities;
+pub mod cli;
+pub mod server;
+pub mod http;
 #[cfg(test)]
 pub mod tests;
\ No newline at end of file
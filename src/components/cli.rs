@@ -0,0 +1,585 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::env::args_os;
+use std::fs::File;
+use std::io::{Write, stdout, stderr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use csv::Writer;
+
+// LOCAL
+use crate::components::data_structures::{PaymentsEngine, AccountOrder, OutputFormat, RecordSink, RejectionReport, Strict, FraudLog, Trace, Statement, AuditReport, FeeBatch, RawClient, EngineConfig, InputFormat, WithdrawalDisputePolicy, NegativeAvailablePolicy, RedisputePolicy};
+use crate::components::server::Server;
+use crate::components::http::HttpServer;
+use crate::components::utilities::{STDIN, process_input, write_accounts, validate_transactions, open_input, diff_accounts, generate_test_data, process_parallel, read_snapshot, save_snapshot, read_opening_balances, open_journal, read_fee_schedule};
+
+pub const USAGE: &str = "\
+Usage: rust_coding_test [COMMAND] [OPTIONS] <INPUT>...
+
+Commands:
+  process   apply transactions and write the resulting accounts (the default when no command is given)
+  replay    apply transactions and write what happened to every record instead of the accounts
+  statement apply transactions and write one client's history with their balances after every step
+  fees      apply transactions and write the fees due on every accepted withdrawal, to post on the next run
+  validate  check that every record can be read, without applying anything
+  diff      compare two accounts files
+  generate  write a file of random transactions
+  serve     accept transaction streams over TCP and apply them all to the same accounts
+  help      print this message
+
+Run `rust_coding_test <COMMAND> --help` for the options of each command.";
+
+pub const PROCESS_USAGE: &str = "\
+Usage: rust_coding_test process [OPTIONS] [INPUT]...
+
+Applies every input, in order, to the same accounts and writes the accounts out. An INPUT of - reads STDIN.
+Inputs ending in .jsonl or .ndjson are read as JSON Lines and everything else as csv, unless --input-format says otherwise.
+
+Options:
+  --input-format <FORMAT>    csv or jsonl, for every input
+  --output <PATH>            write the accounts to PATH instead of STDOUT
+  --output-format <FORMAT>   csv, jsonl or json (default: from the --output extension, otherwise csv)
+  --order <ORDER>            client (default), total, available or first-seen
+  --rejections <PATH>        write every refused record, and why, to PATH
+  --strict                   stop at the first refused record instead of skipping it
+  --restore <PATH>           start from the accounts and transactions in a snapshot instead of from nothing
+  --snapshot <PATH>          write the accounts and transactions to a snapshot at the end, for --restore next time
+  --opening-balances <PATH>  start from the accounts in an accounts csv (total must equal available + held on every row)
+  --journal <PATH>           start from the accounts rebuilt from the journal at PATH, and append every record to it
+                             (no INPUT is needed to just rebuild them, can't be combined with --restore or --opening-balances)
+  --trusted <PATH>           an input from a trusted source, the only place unlock, lock, adjustment, fee and interest are accepted from
+  --audit <PATH>             write every unlock, lock and adjustment, accepted or not, to PATH
+  --withdrawal-disputes <POLICY>
+                             what disputing a withdrawal does: hold (default, held as a potential refund),
+                             reverse (refunded straight away) or reject (withdrawals can't be disputed)
+  --negative-available <POLICY>
+                             whether a dispute may take available below zero: forbid (default, the dispute is refused)
+                             or allow (it goes negative and the client is flagged for review)
+  --redispute <POLICY>       whether a resolved dispute can be disputed again: forbid (default) or allow
+  --authorization-expiry <N> release an authorization that hasn't been captured or voided within N more transactions
+  --threads <N>              apply transactions on N worker threads, each owning the clients whose id % N is theirs
+                             (can't be combined with --rejections, --strict, --journal, --trusted, --audit or
+                             --authorization-expiry, and refuses transfers)";
+
+pub const REPLAY_USAGE: &str = "\
+Usage: rust_coding_test replay [OPTIONS] [INPUT]...
+
+Applies every input, in order, and writes one row per record saying what happened to it
+(the outcome or rejection reason) and the client's balances afterwards.
+With --journal the journal's records are replayed and traced first, so `replay --journal PATH`
+on its own rebuilds the accounts purely from the journal.
+
+Options:
+  --input-format <FORMAT>    csv or jsonl, for every input (default: from each input's extension)
+  --output <PATH>            write the trace to PATH instead of STDOUT
+  --rejections <PATH>        write every refused record, and why, to PATH
+  --strict                   stop at the first refused record instead of skipping it
+  --restore <PATH>           start from the accounts and transactions in a snapshot instead of from nothing
+  --snapshot <PATH>          write the accounts and transactions to a snapshot at the end
+  --opening-balances <PATH>  start from the accounts in an accounts csv
+  --journal <PATH>           replay the journal at PATH first, and append every record to it
+  --trusted <PATH>           an input from a trusted source, the only place unlock, lock, adjustment, fee and interest are accepted from
+  --audit <PATH>             write every unlock, lock and adjustment, accepted or not, to PATH
+  --withdrawal-disputes <POLICY>
+                             what disputing a withdrawal does: hold (default, held as a potential refund),
+                             reverse (refunded straight away) or reject (withdrawals can't be disputed)
+  --negative-available <POLICY>
+                             whether a dispute may take available below zero: forbid (default, the dispute is refused)
+                             or allow (it goes negative and the client is flagged for review)
+  --redispute <POLICY>       whether a resolved dispute can be disputed again: forbid (default) or allow
+  --authorization-expiry <N> release an authorization that hasn't been captured or voided within N more transactions";
+
+pub const STATEMENT_USAGE: &str = "\
+Usage: rust_coding_test statement --client <ID> [OPTIONS] [INPUT]...
+
+Applies every input, in order, and writes every record that touched client ID in the order it was applied:
+their deposits, withdrawals, disputes, resolves and chargebacks, their rejected records, and anyone else's
+attempt to reference one of their transactions. Each row has its section (activity, or fees_and_interest for
+the fees and interest the platform posted), the outcome or rejection reason and the client's available, held
+and total balances afterwards.
+
+Options:
+  --client <ID>              the client to write the statement for (required)
+  --input-format <FORMAT>    csv or jsonl, for every input (default: from each input's extension)
+  --output <PATH>            write the statement to PATH instead of STDOUT
+  --rejections <PATH>        write every refused record, and why, to PATH
+  --strict                   stop at the first refused record instead of skipping it
+  --restore <PATH>           start from the accounts and transactions in a snapshot instead of from nothing
+  --opening-balances <PATH>  start from the accounts in an accounts csv
+  --journal <PATH>           include the client's records from the journal at PATH, and append every record to it
+  --trusted <PATH>           an input from a trusted source, the only place unlock, lock, adjustment, fee and interest are accepted from
+  --withdrawal-disputes <POLICY>
+                             what disputing a withdrawal does: hold (default, held as a potential refund),
+                             reverse (refunded straight away) or reject (withdrawals can't be disputed)
+  --negative-available <POLICY>
+                             whether a dispute may take available below zero: forbid (default, the dispute is refused)
+                             or allow (it goes negative and the client is flagged for review)
+  --redispute <POLICY>       whether a resolved dispute can be disputed again: forbid (default) or allow
+  --authorization-expiry <N> release an authorization that hasn't been captured or voided within N more transactions";
+
+pub const FEES_USAGE: &str = "\
+Usage: rust_coding_test fees --schedule <PATH> --first-tx <ID> [OPTIONS] [INPUT]...
+
+Applies every input, in order, and writes a fee transaction for every withdrawal that was accepted, charged at
+the percentage the schedule gives for the withdrawal's amount. Nothing is recorded anywhere else, the fees are
+written as a csv of transactions to post with --trusted on the next run. The schedule is a csv of from,percent
+tiers, a withdrawal of at least `from` (up to the next tier's) is charged `percent` of its amount.
+
+Options:
+  --schedule <PATH>          the fee schedule (required)
+  --first-tx <ID>            the tx id of the first fee, the rest count up from it (required)
+  --input-format <FORMAT>    csv or jsonl, for every input (default: from each input's extension)
+  --output <PATH>            write the fees to PATH instead of STDOUT
+  --rejections <PATH>        write every refused record, and why, to PATH
+  --strict                   stop at the first refused record instead of skipping it
+  --restore <PATH>           start from the accounts and transactions in a snapshot instead of from nothing
+  --opening-balances <PATH>  start from the accounts in an accounts csv
+  --trusted <PATH>           an input from a trusted source, the only place unlock, lock, adjustment, fee and interest are accepted from
+  --withdrawal-disputes <POLICY>
+                             what disputing a withdrawal does: hold (default, held as a potential refund),
+                             reverse (refunded straight away) or reject (withdrawals can't be disputed)
+  --negative-available <POLICY>
+                             whether a dispute may take available below zero: forbid (default, the dispute is refused)
+                             or allow (it goes negative and the client is flagged for review)
+  --redispute <POLICY>       whether a resolved dispute can be disputed again: forbid (default) or allow
+  --authorization-expiry <N> release an authorization that hasn't been captured or voided within N more transactions";
+
+pub const VALIDATE_USAGE: &str = "\
+Usage: rust_coding_test validate [OPTIONS] <INPUT>...
+
+Reads every record without applying it and reports the ones that can't be read as a transaction.
+Exits with status 1 if any record is invalid.
+
+Options:
+  --input-format <FORMAT>  csv or jsonl, for every input (default: from each input's extension)
+  --output <PATH>          write the report to PATH instead of STDOUT";
+
+pub const DIFF_USAGE: &str = "\
+Usage: rust_coding_test diff <LEFT> <RIGHT>
+
+Compares two accounts files (as written by process) and writes every client that differs, one row per side.
+Exits with status 1 if the files differ.";
+
+pub const GENERATE_USAGE: &str = "\
+Usage: rust_coding_test generate [OPTIONS]
+
+Options:
+  --output <PATH>  where to write the transactions (default: test_data.csv)
+  --rows <N>       how many transactions to write (default: 30)";
+
+pub const SERVE_USAGE: &str = "\
+Usage: rust_coding_test serve [OPTIONS]
+
+Listens for TCP connections, each one either streams csv transactions (header first) and gets back
+how many were accepted and rejected once it shuts down its side, or sends ACCOUNTS on its own line
+and gets back the current accounts. Every connection is applied to the same accounts.
+With --http the same accounts are also served as JSON: POST /transactions, GET /accounts,
+GET /accounts/{client} and GET /accounts/{client}/transactions.
+
+Options:
+  --listen <ADDRESS>  address to listen on (default: 127.0.0.1:7878)
+  --http <ADDRESS>    also serve the HTTP API on ADDRESS
+  --order <ORDER>     order of the accounts sent back for ACCOUNTS: client (default), total, available or first-seen";
+
+// Command is what we've been asked to do on the command line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Process(Arguments),
+    Replay(Arguments),
+    Statement { client: u16, arguments: Arguments },
+    Fees { schedule: OsString, first_tx: u32, arguments: Arguments },
+    Validate(Arguments),
+    Diff { left: OsString, right: OsString },
+    Generate { output: OsString, rows: u32 },
+    Serve { listen: String, http: Option<String>, order: AccountOrder },
+    Help(&'static str),
+}
+
+// Arguments holds the options shared by the commands that read transactions
+#[derive(Debug, PartialEq, Default)]
+pub struct Arguments {
+    pub inputs: Vec<OsString>, // processed in order into the same accounts, "-" reads STDIN
+    pub output: Option<OsString>, // None writes to STDOUT
+    pub input_format: Option<InputFormat>, // None picks each input's format from its extension
+    pub output_format: Option<OutputFormat>, // None picks the format from the output's extension
+    pub order: AccountOrder,
+    pub rejections: Option<OsString>, // where to write the rejection report, if anywhere
+    pub strict: bool,
+    pub threads: Option<usize>, // None processes on this thread, Some(n) shards clients across n workers
+    pub restore: Option<OsString>, // a snapshot to start from
+    pub snapshot: Option<OsString>, // where to write a snapshot once every input is processed
+    pub opening_balances: Option<OsString>, // an accounts csv to start from
+    pub journal: Option<OsString>, // a journal to rebuild the accounts from and append to
+    pub trusted: Vec<usize>, // positions in inputs whose admin transactions are accepted, by position so the same path (or -) given untrusted elsewhere stays untrusted
+    pub audit: Option<OsString>, // where to write the audit report of admin transactions, if anywhere
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    pub negative_available: NegativeAvailablePolicy,
+    pub redispute: RedisputePolicy,
+    pub authorization_expiry: Option<u64>, // None leaves authorizations open until they're captured or voided
+}
+
+impl Arguments {
+    // the format to read an input in, --input-format if we were given one and the input's extension otherwise
+    pub fn input_format(&self, input: &OsString) -> InputFormat {
+        self.input_format.unwrap_or_else(|| InputFormat::from_path(input))
+    }
+    // whether the input at this position in inputs was passed with --trusted
+    pub fn is_trusted(&self, position: usize) -> bool {
+        self.trusted.contains(&position)
+    }
+    // the policies to run the engine with
+    pub fn config(&self) -> EngineConfig {
+        EngineConfig {
+            withdrawal_disputes: self.withdrawal_disputes,
+            negative_available: self.negative_available,
+            redispute: self.redispute,
+            authorization_expiry: self.authorization_expiry,
+        }
+    }
+}
+
+// function to parse the command and its options from the command line
+pub fn parse_args() -> Result<Command, Box<dyn Error>> {
+    // collect args into vector, I chose OsString to avoid any encoding issues on different platforms
+    // the first element is the name of the program itself, so we skip it - this is convention in major OSes
+    parse_command(args_os().skip(1).collect())
+}
+
+// the actual parsing lives here so it can be tested without touching the real command line
+pub fn parse_command(mut args: Vec<OsString>) -> Result<Command, Box<dyn Error>> {
+    let first = args.first().and_then(|arg| arg.to_str()).unwrap_or("help").to_string();
+    // anything that isn't a command is an input, so `rust_coding_test input.csv` keeps working
+    let (name, usage) = match first.as_str() {
+        "help" | "--help" | "-h" => return Ok(Command::Help(USAGE)),
+        "process" => ("process", PROCESS_USAGE),
+        "replay" => ("replay", REPLAY_USAGE),
+        "statement" => ("statement", STATEMENT_USAGE),
+        "fees" => ("fees", FEES_USAGE),
+        "validate" => ("validate", VALIDATE_USAGE),
+        "diff" => ("diff", DIFF_USAGE),
+        "generate" => ("generate", GENERATE_USAGE),
+        "serve" => ("serve", SERVE_USAGE),
+        _ => ("", PROCESS_USAGE),
+    };
+    if !name.is_empty() {
+        args.remove(0);
+    }
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return Ok(Command::Help(usage));
+    }
+    match name {
+        "diff" => match <[OsString; 2]>::try_from(args) {
+            Ok([left, right]) => Ok(Command::Diff { left, right }),
+            Err(_) => Err(DIFF_USAGE.into()),
+        },
+        "generate" => parse_generate(args),
+        "serve" => parse_serve(args),
+        "statement" => parse_statement(args),
+        "fees" => parse_fees(args),
+        "replay" => Ok(Command::Replay(process_only(parse_arguments(args, usage)?, usage)?)),
+        "validate" => Ok(Command::Validate(without_engine(process_only(parse_arguments(args, usage)?, usage)?, usage)?)),
+        _ => Ok(Command::Process(parse_arguments(args, usage)?)),
+    }
+}
+
+// pull the value that must follow a flag
+fn flag_value(flag: &str, args: &mut impl Iterator<Item = OsString>) -> Result<OsString, Box<dyn Error>> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag).into())
+}
+
+fn parse_arguments(args: Vec<OsString>, usage: &str) -> Result<Arguments, Box<dyn Error>> {
+    let mut arguments = Arguments::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--output") => arguments.output = Some(flag_value("--output", &mut args)?),
+            Some("--input-format") => arguments.input_format = Some(flag_value("--input-format", &mut args)?.to_string_lossy().parse()?),
+            Some("--output-format") => arguments.output_format = Some(flag_value("--output-format", &mut args)?.to_string_lossy().parse()?),
+            Some("--order") => arguments.order = flag_value("--order", &mut args)?.to_string_lossy().parse()?,
+            Some("--rejections") => arguments.rejections = Some(flag_value("--rejections", &mut args)?),
+            Some("--strict") => arguments.strict = true,
+            Some("--restore") => arguments.restore = Some(flag_value("--restore", &mut args)?),
+            Some("--snapshot") => arguments.snapshot = Some(flag_value("--snapshot", &mut args)?),
+            Some("--opening-balances") => arguments.opening_balances = Some(flag_value("--opening-balances", &mut args)?),
+            Some("--journal") => arguments.journal = Some(flag_value("--journal", &mut args)?),
+            Some("--trusted") => {
+                arguments.trusted.push(arguments.inputs.len());
+                arguments.inputs.push(flag_value("--trusted", &mut args)?);
+            },
+            Some("--audit") => arguments.audit = Some(flag_value("--audit", &mut args)?),
+            Some("--withdrawal-disputes") => arguments.withdrawal_disputes = flag_value("--withdrawal-disputes", &mut args)?.to_string_lossy().parse()?,
+            Some("--negative-available") => arguments.negative_available = flag_value("--negative-available", &mut args)?.to_string_lossy().parse()?,
+            Some("--redispute") => arguments.redispute = flag_value("--redispute", &mut args)?.to_string_lossy().parse()?,
+            Some("--authorization-expiry") => arguments.authorization_expiry = match flag_value("--authorization-expiry", &mut args)?.to_string_lossy().parse() {
+                Ok(window) => Some(window),
+                _ => return Err("--authorization-expiry requires a whole number of transactions".into()),
+            },
+            Some("--threads") => arguments.threads = match flag_value("--threads", &mut args)?.to_string_lossy().parse() {
+                Ok(threads) if threads > 0 => Some(threads),
+                _ => return Err("--threads requires a whole number greater than 0".into()),
+            },
+            Some(flag) if flag.starts_with("--") => return Err(format!("unknown option: {}\n\n{}", flag, usage).into()),
+            _ => arguments.inputs.push(arg),
+        }
+    }
+    // a journal on its own is enough, the accounts are rebuilt from it
+    if arguments.inputs.is_empty() && arguments.journal.is_none() {
+        return Err(usage.into());
+    }
+    // a snapshot already has its own balances, and a journal has to hold every record behind its accounts
+    if [&arguments.restore, &arguments.opening_balances, &arguments.journal].iter().filter(|path| path.is_some()).count() > 1 {
+        return Err(format!("--restore, --opening-balances and --journal can't be used together\n\n{}", usage).into());
+    }
+    // records are applied on other threads in parallel, so nothing can watch them one by one (to report, stop at, journal or audit them)
+    // and the shards only ever apply transactions from untrusted sources, each counting only its own towards authorization expiry
+    if arguments.threads.is_some() && (arguments.rejections.is_some() || arguments.strict || arguments.journal.is_some() || !arguments.trusted.is_empty() || arguments.audit.is_some() || arguments.authorization_expiry.is_some()) {
+        return Err(format!("--threads can't be combined with --rejections, --strict, --journal, --trusted, --audit or --authorization-expiry\n\n{}", usage).into());
+    }
+    // we check to see if each argument is "-" (STDIN) or a regular file and perform our own error handling
+    for input in &arguments.inputs {
+        if input != STDIN && !Path::new(input).is_file() {
+            return Err(format!("file does not exist: {}", input.to_string_lossy()).into());
+        }
+    }
+    Ok(arguments)
+}
+
+// replay and validate don't write accounts, and only process can run in parallel
+fn process_only(arguments: Arguments, usage: &str) -> Result<Arguments, Box<dyn Error>> {
+    match arguments {
+        Arguments { threads: Some(_), .. } => Err(format!("--threads can only be used with process\n\n{}", usage).into()),
+        Arguments { output_format: Some(_), .. } => Err(format!("--output-format can only be used with process\n\n{}", usage).into()),
+        arguments => Ok(arguments),
+    }
+}
+
+// validate never builds any accounts, so there's nothing to start from or snapshot
+fn without_engine(arguments: Arguments, usage: &str) -> Result<Arguments, Box<dyn Error>> {
+    match arguments.restore.is_some() || arguments.snapshot.is_some() || arguments.opening_balances.is_some() || arguments.journal.is_some() {
+        true => Err(format!("--restore, --snapshot, --opening-balances and --journal can't be used with validate\n\n{}", usage).into()),
+        false => Ok(arguments),
+    }
+}
+
+// take a flag a command requires, along with its value, out of the args before the usual options are parsed
+fn take_required(args: &mut Vec<OsString>, flag: &str, usage: &str) -> Result<OsString, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) if index + 1 < args.len() => Ok(args.drain(index..index + 2).nth(1).unwrap_or_default()),
+        Some(_) => Err(format!("{} requires a value", flag).into()),
+        None => Err(format!("{} is required\n\n{}", flag, usage).into()),
+    }
+}
+
+// statement takes the usual options plus the client it's for
+fn parse_statement(mut args: Vec<OsString>) -> Result<Command, Box<dyn Error>> {
+    let client = take_required(&mut args, "--client", STATEMENT_USAGE)?
+        .to_string_lossy()
+        .parse()
+        .map_err(|_| format!("--client requires a client id between 0 and {}", u16::MAX))?;
+    let arguments = process_only(parse_arguments(args, STATEMENT_USAGE)?, STATEMENT_USAGE)?;
+    if arguments.snapshot.is_some() {
+        return Err(format!("--snapshot can't be used with statement\n\n{}", STATEMENT_USAGE).into());
+    }
+    Ok(Command::Statement { client, arguments })
+}
+
+// fees takes the usual options plus the schedule and where the fees' tx ids start
+// it only works out the fees, so there's no journal to append to or snapshot to write
+fn parse_fees(mut args: Vec<OsString>) -> Result<Command, Box<dyn Error>> {
+    let schedule = take_required(&mut args, "--schedule", FEES_USAGE)?;
+    let first_tx = take_required(&mut args, "--first-tx", FEES_USAGE)?
+        .to_string_lossy()
+        .parse()
+        .map_err(|_| format!("--first-tx requires a tx id between 0 and {}", u32::MAX))?;
+    let arguments = process_only(parse_arguments(args, FEES_USAGE)?, FEES_USAGE)?;
+    if arguments.snapshot.is_some() || arguments.journal.is_some() || arguments.audit.is_some() {
+        return Err(format!("--snapshot, --journal and --audit can't be used with fees\n\n{}", FEES_USAGE).into());
+    }
+    Ok(Command::Fees { schedule, first_tx, arguments })
+}
+
+fn parse_generate(args: Vec<OsString>) -> Result<Command, Box<dyn Error>> {
+    let mut output = OsString::from("test_data.csv");
+    let mut rows = 30;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--output") => output = flag_value("--output", &mut args)?,
+            Some("--rows") => rows = flag_value("--rows", &mut args)?.to_string_lossy().parse().map_err(|_| "--rows requires a whole number")?,
+            _ => return Err(format!("unexpected argument: {}\n\n{}", arg.to_string_lossy(), GENERATE_USAGE).into()),
+        }
+    }
+    Ok(Command::Generate { output, rows })
+}
+
+fn parse_serve(args: Vec<OsString>) -> Result<Command, Box<dyn Error>> {
+    let mut listen = String::from("127.0.0.1:7878");
+    let mut http = None;
+    let mut order = AccountOrder::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--listen") => listen = flag_value("--listen", &mut args)?.to_string_lossy().to_string(),
+            Some("--http") => http = Some(flag_value("--http", &mut args)?.to_string_lossy().to_string()),
+            Some("--order") => order = flag_value("--order", &mut args)?.to_string_lossy().parse()?,
+            _ => return Err(format!("unexpected argument: {}\n\n{}", arg.to_string_lossy(), SERVE_USAGE).into()),
+        }
+    }
+    Ok(Command::Serve { listen, http, order })
+}
+
+// where a command's main output goes, a file if we were given one and STDOUT otherwise
+fn open_output(path: &Option<OsString>) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    match path {
+        Some(path) => Ok(Box::new(File::create(path)?)),
+        None => Ok(Box::new(stdout())),
+    }
+}
+
+// the sinks every command that applies transactions shares: the rejection report, the audit report, fraud warnings on STDERR, then strict mode
+fn record_sinks(arguments: &Arguments) -> Result<Vec<Box<dyn RecordSink>>, Box<dyn Error>> {
+    let mut sinks: Vec<Box<dyn RecordSink>> = Vec::new();
+    if let Some(path) = &arguments.rejections {
+        sinks.push(Box::new(RejectionReport::new(File::create(path)?)));
+    }
+    if let Some(path) = &arguments.audit {
+        sinks.push(Box::new(AuditReport::new(File::create(path)?)));
+    }
+    sinks.push(Box::new(FraudLog::new(stderr())));
+    if arguments.strict {
+        sinks.push(Box::new(Strict));
+    }
+    Ok(sinks)
+}
+
+// the engine a command starts from: restored from a snapshot, seeded with opening balances, or empty
+fn starting_engine(arguments: &Arguments) -> Result<PaymentsEngine, Box<dyn Error>> {
+    match (&arguments.restore, &arguments.opening_balances) {
+        (Some(path), _) => read_snapshot(File::open(path)?, arguments.config()),
+        (None, Some(path)) => read_opening_balances(File::open(path)?, arguments.config()),
+        (None, None) => Ok(PaymentsEngine::with_config(arguments.config())),
+    }
+}
+
+// run a command, returning the exit code the process should finish with
+pub fn run(command: Command) -> Result<i32, Box<dyn Error>> {
+    match command {
+        Command::Process(arguments) => {
+            let engine = match arguments.threads {
+                Some(threads) => process_parallel(&arguments.inputs, arguments.input_format, starting_engine(&arguments)?, threads, &mut FraudLog::new(stderr()))?,
+                None => {
+                    let mut engine = starting_engine(&arguments)?;
+                    let mut sinks = record_sinks(&arguments)?;
+                    // the journal goes first so the record that trips strict mode is still journaled
+                    if let Some(path) = &arguments.journal {
+                        sinks.insert(0, Box::new(open_journal(path, &mut engine, &mut ())?));
+                    }
+                    for (position, input) in arguments.inputs.iter().enumerate() {
+                        process_input(input, arguments.input_format(input), arguments.is_trusted(position), &mut engine, &mut sinks)?;
+                    }
+                    engine
+                },
+            };
+            let format = arguments.output_format
+                .or_else(|| arguments.output.as_deref().map(OutputFormat::from_path))
+                .unwrap_or_default();
+            write_accounts(&engine, arguments.order, format, open_output(&arguments.output)?)?;
+            if let Some(path) = &arguments.snapshot {
+                save_snapshot(path, &engine)?;
+            }
+            Ok(0)
+        },
+        Command::Replay(arguments) => {
+            let mut engine = starting_engine(&arguments)?;
+            let mut sinks = record_sinks(&arguments)?;
+            let mut trace = Trace::new(open_output(&arguments.output)?);
+            if let Some(path) = &arguments.journal {
+                sinks.insert(0, Box::new(open_journal(path, &mut engine, &mut trace)?));
+            }
+            // the trace goes first so the record that trips strict mode still shows up in it
+            sinks.insert(0, Box::new(trace));
+            for (position, input) in arguments.inputs.iter().enumerate() {
+                process_input(input, arguments.input_format(input), arguments.is_trusted(position), &mut engine, &mut sinks)?;
+            }
+            if let Some(path) = &arguments.snapshot {
+                save_snapshot(path, &engine)?;
+            }
+            Ok(0)
+        },
+        Command::Statement { client, arguments } => {
+            let mut engine = starting_engine(&arguments)?;
+            let mut sinks = record_sinks(&arguments)?;
+            let mut statement = Statement::new(open_output(&arguments.output)?, client);
+            // the client's history goes back to the start of the journal
+            if let Some(path) = &arguments.journal {
+                sinks.insert(0, Box::new(open_journal(path, &mut engine, &mut statement)?));
+            }
+            sinks.insert(0, Box::new(statement));
+            for (position, input) in arguments.inputs.iter().enumerate() {
+                process_input(input, arguments.input_format(input), arguments.is_trusted(position), &mut engine, &mut sinks)?;
+            }
+            Ok(0)
+        },
+        Command::Fees { schedule, first_tx, arguments } => {
+            let schedule = read_fee_schedule(File::open(&schedule)?)?;
+            let mut engine = starting_engine(&arguments)?;
+            let mut sinks = record_sinks(&arguments)?;
+            sinks.insert(0, Box::new(FeeBatch::new(open_output(&arguments.output)?, schedule, first_tx)));
+            for (position, input) in arguments.inputs.iter().enumerate() {
+                process_input(input, arguments.input_format(input), arguments.is_trusted(position), &mut engine, &mut sinks)?;
+            }
+            Ok(0)
+        },
+        Command::Validate(arguments) => {
+            let mut report = RejectionReport::new(open_output(&arguments.output)?);
+            let mut invalid = 0;
+            for input in &arguments.inputs {
+                invalid += validate_transactions(open_input(input)?, arguments.input_format(input), &input.to_string_lossy(), &mut report)?;
+            }
+            eprintln!("{} invalid record(s) found.", invalid);
+            Ok(if invalid == 0 { 0 } else { 1 })
+        },
+        Command::Diff { left, right } => {
+            let differences = diff_accounts(open_input(&left)?, open_input(&right)?)?;
+            let mut writer = Writer::from_writer(stdout());
+            // the flagged column is only there if the files have it
+            let flags = differences.iter().flat_map(|difference| [&difference.left, &difference.right]).flatten().any(|account| account.flagged.is_some());
+            let headers = ["client", "side", "available", "held", "total", "locked", "flagged"];
+            writer.write_record(&headers[..if flags { 7 } else { 6 }])?;
+            for difference in &differences {
+                let sides = [("left", &difference.left), ("right", &difference.right)];
+                for (side, account) in sides.iter().filter_map(|(side, account)| account.as_ref().map(|account| (side, account))) {
+                    let RawClient { client, available, held, total, locked, flagged } = account;
+                    let mut row = vec![client.to_string(), side.to_string(), available.to_string(), held.to_string(), total.to_string(), locked.to_string()];
+                    row.extend(flagged.map(|flagged| flagged.to_string()));
+                    writer.write_record(&row)?;
+                }
+            }
+            writer.flush()?;
+            Ok(if differences.is_empty() { 0 } else { 1 })
+        },
+        Command::Generate { output, rows } => {
+            generate_test_data(&output, rows)?;
+            Ok(0)
+        },
+        Command::Serve { listen, http, order } => {
+            let engine = Arc::new(Mutex::new(PaymentsEngine::new()));
+            if let Some(address) = http {
+                let http = HttpServer::bind(address, Arc::clone(&engine))?;
+                if let Some(address) = http.local_addr() {
+                    eprintln!("Serving HTTP on {}", address);
+                }
+                thread::spawn(move || http.run());
+            }
+            let server = Server::bind(listen, engine, order)?;
+            eprintln!("Listening on {}", server.local_addr()?);
+            server.run()?;
+            Ok(0)
+        },
+        Command::Help(usage) => {
+            println!("{}", usage);
+            Ok(0)
+        },
+    }
+}
@@ -1,7 +1,12 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::io::{BufWriter, Write};
+use csv::{StringRecord, Writer};
 use serde::{Serialize, Deserialize};
 
 // this enum represents all the forms a "transaction" can take
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TXType {
     Deposit,
@@ -9,41 +14,479 @@ pub enum TXType {
     Dispute,
     Resolve,
     Chargeback,
+    Transfer, // moves an amount from client to the client in the to column, both accounts change or neither does
+    // postings the platform makes on a client's account, only accepted from a trusted source and never disputable
+    Fee, // taken from available and total like a withdrawal
+    Interest, // added to available and total like a deposit
+    // two-phase card payments, an authorization holds funds until it's captured, voided or expires (see EngineConfig::authorization_expiry)
+    Authorize, // moves the amount from available to held
+    Capture, // settles the authorization with tx, taking all of it (no amount) or part of it out of held and total, the rest goes back to available
+    Void, // releases the authorization with tx, its funds go back to available
+    // admin transactions, only accepted from a trusted source (see PaymentsEngine::apply_trusted)
+    Unlock,
+    Lock,
+    Adjustment, // a signed amount added to available and total, with a reason
 }
 
 // type interface for TXType
 pub type TransactionType = TXType;
 
+// every reason the engine can reject a transaction for, each variant carries enough context for callers to act on it without parsing messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    AccountLocked { client: u16, tx: u32 },
+    InsufficientFunds { client: u16, tx: u32, available: Amount, requested: Amount },
+    UnknownTransaction { client: u16, tx: u32 },
+    AlreadyDisputed { client: u16, tx: u32 },
+    NotDisputed { client: u16, tx: u32 },
+    AlreadySettled { client: u16, tx: u32 },
+    MissingAmount { client: u16, tx: u32 },
+    Overflow { client: u16, tx: u32, amount: Amount },
+    ClientMismatch { client: u16, tx: u32, owner: u16 },
+    DuplicateTransaction { client: u16, tx: u32 },
+    Untrusted { client: u16, tx: u32 }, // an admin transaction, fee or interest from a source that isn't trusted
+    NotDisputable { client: u16, tx: u32 }, // a fee, interest, adjustment or authorization, or a withdrawal under WithdrawalDisputePolicy::Reject
+    InvalidDestination { client: u16, tx: u32 }, // a transfer without a to client, or to the client sending it
+    ExceedsAuthorization { client: u16, tx: u32, authorized: Amount, requested: Amount }, // a capture for more than was authorized
+    Parse(String), // the record could not be read as a transaction at all
+}
+
+// every transaction that moved funds, keyed by tx id so a dispute, resolve or chargeback can find it in constant time
+// its keys are also every tx id we've accepted, which is what replays are checked against
+pub type TransactionIndex = HashMap<u32, TransactionRecord>;
+
+// PaymentsEngine owns every client account, transactions are fed into it one at a time through PaymentsEngine::apply
+#[derive(Debug, Default)]
+pub struct PaymentsEngine {
+    pub(crate) clients: HashMap<u16, Client>,
+    pub(crate) transactions: TransactionIndex,
+    pub(crate) config: EngineConfig,
+    pub(crate) first_seen: Vec<u16>, // client ids in the order we first heard of them
+    pub(crate) sequence: u64, // how many transactions have been applied, authorizations expire after a number of them
+    pub(crate) expiries: BinaryHeap<Reverse<(u64, u32)>>, // (sequence an authorization expires after, tx) for every one that can expire
+}
+
+// InputFormat is how transactions are read in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    Jsonl, // one JSON object shaped like RawTransaction per line
+}
+
+// OutputFormat is how accounts are written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Jsonl, // one JSON object shaped like RawClient per line
+    Json, // a single JSON array of RawClients
+}
+
+// AccountOrder is the order accounts are written out in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountOrder {
+    #[default]
+    ClientId, // ascending client id
+    Total, // largest total balance first, ties broken by client id
+    Available, // largest available balance first, ties broken by client id
+    FirstSeen, // the order clients first appeared in the input
+}
+
+// TxIdSet is a bitmap with one bit for every possible u32 tx id
+// it is split into pages of 65536 ids (8KiB each) which are only allocated once an id in that range is used,
+// so a file with densely packed ids costs about one bit per transaction and the whole u32 space tops out at 512MiB
+#[derive(Debug, Default)]
+pub struct TxIdSet {
+    pub(crate) pages: Vec<Option<Box<[u64]>>>,
+    pub(crate) len: usize,
+}
+
+// TxOwners remembers which client owns each deposit, withdrawal or authorization tx id
+// the parallel reader uses it to catch ids shared between clients, which live on different shards and would never see each other
+// owners are kept in pages of 65536 u16s (128KiB each) next to a TxIdSet saying which ids are claimed
+// a claim is pending until the claiming client's shard has accepted it, until then the page holds the client claiming it
+#[derive(Debug, Default)]
+pub struct TxOwners {
+    pub(crate) claimed: TxIdSet,
+    pub(crate) pending: TxIdSet,
+    pub(crate) repeats: HashMap<u32, usize>, // further claims on a pending id by the same client, there's hardly ever anything in here
+    pub(crate) pages: Vec<Option<Box<[u16]>>>,
+}
+
+// Outcome describes what an accepted transaction did, along with the amount it moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Deposited(Amount),
+    Withdrawn(Amount),
+    Disputed(Amount),
+    Resolved(Amount),
+    ChargedBack(Amount),
+    Unlocked,
+    Locked,
+    Adjusted(Amount), // negative if the adjustment took funds away
+    Transferred(Amount),
+    FeeCharged(Amount),
+    InterestPaid(Amount),
+    Authorized(Amount),
+    Captured(Amount), // the amount captured, the rest of the authorization went back to available
+    Voided(Amount),
+}
+
+// Amount is a fixed-point value with four decimal places, stored as a whole number of ten-thousandths (0.0001 == Amount(1))
+// it is parsed directly from the input text, so a balance never has to pass through a floating point type
+// it is signed so an adjustment can take funds away, but parsing one with FromStr still refuses anything negative (see Amount::parse_signed)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(pub(crate) i64);
+
+// everything that can go wrong when reading an Amount from text
+#[derive(Debug, PartialEq, Eq)]
+pub enum AmountError {
+    Invalid(String),
+    Negative(String),
+    TooPrecise(String),
+    Overflow(String),
+}
+
 #[derive(Debug)]
 pub struct Client {
     pub client: u16, 
-    pub available: u64,
-    pub held: u64, 
-    pub total: u64,
-    pub transactions: Vec<Transaction>,
+    pub available: Amount,
+    pub held: Amount, 
+    pub total: Amount,
     pub locked: bool,
+    pub flagged: bool, // needs a review, set when a dispute took available below zero (see NegativeAvailablePolicy)
 }
 
 // RawClient is what gets written to the output file then RawClient gets converted "Into" Client
-#[derive(Debug, Serialize, PartialEq)]
+// available and total can be negative after a dispute under NegativeAvailablePolicy::AllowUnderDispute
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct RawClient {
     pub client: u16, 
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    #[serde(deserialize_with = "Amount::deserialize_signed")]
+    pub available: Amount,
+    pub held: Amount,
+    #[serde(deserialize_with = "Amount::deserialize_signed")]
+    pub total: Amount,
     pub locked: bool,
+    // see Client::flagged, the column is only written where an account can be flagged (NegativeAvailablePolicy::AllowUnderDispute)
+    // and snapshots, so an accounts file without it reads as nobody flagged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<bool>,
 }
 
-// Transaction is our "source of truth" for each transaction while we are processing the data, it contains a flag which allows us to tell if it's been disputed
-// We store the decimal values as integers in Transaction while we are performing arithmetic operations to avoid rounding errors and the performance hit of other crates
-#[derive(Debug)]
+// Transaction is our "source of truth" for each transaction while we are processing the data
+// Amounts are kept as fixed-point integers (see Amount) while we are performing arithmetic operations to avoid rounding errors
+#[derive(Debug, Clone, PartialEq)]
 pub struct Transaction {
     pub transaction_type: TransactionType, 
     pub client: u16,
     pub tx: u32,
-    pub disputed: bool,
-    pub resolved: bool,
-    pub amount: Option<u64>, // we use an Option here since not all transaction types have an amount.
+    pub amount: Option<Amount>, // we use an Option here since not all transaction types have an amount.
+    pub reason: Option<String>, // why an admin transaction was made, for the audit report
+    pub to: Option<u16>, // the client a transfer pays, None for every other type
+}
+
+// TransactionRecord is all the engine remembers about an accepted deposit, withdrawal or transfer, just enough to dispute it later
+// its DisputeState tells us where it is in the dispute lifecycle
+// an authorization goes through the same lifecycle, as its funds are held the same way: it starts out Disputed,
+// a void or expiry Resolves it and a capture is its ChargedBack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionRecord {
+    pub transaction_type: TransactionType,
+    pub client: u16, // for a transfer this is the sender, the only client who can dispute it
+    pub amount: Amount,
+    pub state: DisputeState,
+    pub to: Option<u16>, // a transfer's recipient, whose funds are held while it's disputed
+    pub expires: Option<u64>, // the engine sequence an authorization is released after, if authorizations expire
+    pub dispute_policy: Option<WithdrawalDisputePolicy>, // the policy a withdrawal's dispute was opened under, it's settled the same way whatever the config says by then
+}
+
+// the lifecycle of a deposit or withdrawal, every move between states goes through DisputeState::transition
+//   Processed -> Disputed -> Resolved | ChargedBack
+// and, if RedisputePolicy::Allow is configured, Resolved -> Disputed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+// the transactions that move a deposit or withdrawal between DisputeStates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeEvent {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+// whether a transaction whose dispute was resolved can be disputed again, a charged back transaction never can be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedisputePolicy {
+    #[default]
+    Forbid,
+    Allow,
+}
+
+// what a dispute of a withdrawal does, for deposits there's only one sensible answer (hold the deposited funds)
+// each policy ends up in the same place once the dispute is settled, a resolve refunds the withdrawal and a chargeback lets it stand,
+// they only differ in what the client can use in the meantime
+// snapshots write it the way it's given on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WithdrawalDisputePolicy {
+    #[serde(rename = "reject")]
+    Reject, // withdrawals can't be disputed at all
+    #[default]
+    #[serde(rename = "hold")]
+    HoldAsRefund, // the disputed amount is added to held and total as a potential refund, it becomes available on resolve
+    #[serde(rename = "reverse")]
+    ReverseImmediately, // the disputed amount goes straight back to available and total, a chargeback takes it away again
+}
+
+// whether a dispute can take more than the client has available, e.g. a deposit that was withdrawn before it was disputed
+// withdrawals can never overdraw, whatever the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeAvailablePolicy {
+    #[default]
+    Forbid, // the dispute is refused with insufficient_funds
+    AllowUnderDispute, // available (and after a chargeback, total) goes negative and the account is flagged for review
+}
+
+// EngineConfig holds the policies a PaymentsEngine runs with, the defaults match the assumptions in the README
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EngineConfig {
+    pub redispute: RedisputePolicy,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    pub negative_available: NegativeAvailablePolicy,
+    pub authorization_expiry: Option<u64>, // how many more transactions an authorization stays open for, None never expires them
+}
+
+// RejectedRecord is one row of the rejection report, it ties a refused transaction back to where it came from in the input
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RejectedRecord {
+    pub input: String, // the file the record came from, or "-" for STDIN
+    pub record: u64, // 1-based position of the record in that input, not counting the header
+    pub row: String, // the record as it was read (after trimming)
+    pub reason: String, // machine readable reason code, see EngineError::code
+    pub detail: String, // human readable description of the same problem
+}
+
+// AccountDifference is one client whose account doesn't match between two accounts files, None means the client is missing from that side
+#[derive(Debug, PartialEq)]
+pub struct AccountDifference {
+    pub client: u16,
+    pub left: Option<RawClient>,
+    pub right: Option<RawClient>,
+}
+
+// ProcessedRecord is everything we know about one input record once the engine has seen it, it gets handed to a RecordSink
+#[derive(Debug)]
+pub struct ProcessedRecord<'a> {
+    pub input: &'a str, // the file the record came from, or "-" for STDIN
+    pub record: u64, // 1-based position of the record in that input, not counting the header
+    pub row: &'a StringRecord, // the record as it was read (after trimming)
+    pub transaction: Option<&'a Transaction>, // None if the record couldn't be read as a transaction
+    pub result: &'a Result<Outcome, EngineError>,
+    pub trusted: bool, // whether the record came from a trusted source, see PaymentsEngine::apply_trusted
+}
+
+// a RecordSink is told about every record as it is processed, this is how reports, traces and strict mode hook into the read loop
+// returning an error from record stops processing
+pub trait RecordSink {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>>;
+    // called once an input has been fully read
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// RejectionReport writes a RejectedRecord for every record the engine refused
+pub struct RejectionReport<W: Write> {
+    pub(crate) writer: Writer<W>,
+}
+
+// Strict stops processing at the first record the engine refuses
+#[derive(Debug, Default)]
+pub struct Strict;
+
+// FraudLog writes a warning line for every client reaching for a tx id that belongs to someone else,
+// and for every dispute or chargeback that leaves a client's available balance negative (they're flagged for review)
+// it's a sink of its own so every way into the engine raises the same alert, whether or not anyone asked for a rejection report
+pub struct FraudLog<W: Write> {
+    pub(crate) writer: W,
+}
+
+// TraceRecord is one row of a replay trace, what happened to a record and where it left the client's balances
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TraceRecord {
+    pub input: String,
+    pub record: u64,
+    pub row: String,
+    pub outcome: String, // e.g. deposited, disputed, or the rejection reason code
+    #[serde(default, deserialize_with = "Amount::deserialize_signed_option")]
+    pub amount: Option<Amount>, // the amount the transaction moved, if it was accepted
+    pub available: Option<Amount>, // the client's balances after the record, if it could be read
+    pub held: Option<Amount>,
+    pub total: Option<Amount>,
+    pub locked: Option<bool>,
+}
+
+// the parts of a statement, a client's own activity and what the platform posted on their account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementSection {
+    Activity, // everything the client (or someone reaching for their transactions) did
+    FeesAndInterest,
+}
+
+// StatementRecord is one row of a statement, a TraceRecord with the section it belongs to in front
+// the rows stay in the order they were applied so the balances still follow on, filter on section to see one part on its own
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct StatementRecord {
+    pub section: StatementSection,
+    pub input: String,
+    pub record: u64,
+    pub row: String,
+    pub outcome: String,
+    #[serde(default, deserialize_with = "Amount::deserialize_signed_option")]
+    pub amount: Option<Amount>,
+    pub available: Option<Amount>,
+    pub held: Option<Amount>,
+    pub total: Option<Amount>,
+    pub locked: Option<bool>,
+}
+
+// RawTransactionRecord is a remembered deposit or withdrawal as the HTTP API shows it
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RawTransactionRecord {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub client: u16,
+    #[serde(deserialize_with = "Amount::deserialize_signed")]
+    pub amount: Amount, // an adjustment's can be negative
+    pub state: DisputeState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u16>, // only transfers have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>, // only authorizations that can expire have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispute_policy: Option<WithdrawalDisputePolicy>, // only withdrawals that have been disputed have one
+}
+
+// TransactionResponse is what the HTTP API says about each submitted transaction
+// accepted transactions carry the outcome and amount, rejected ones the reason code and a description
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TransactionResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<u32>, // None if the transaction couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<u16>,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// SnapshotHeader is the first line of a snapshot file, followed by one RawClient line per account (in first-seen order)
+// and one RawTransactionRecord line per disputable transaction (by tx id)
+// the counts let us tell a complete snapshot from one that was cut short
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub accounts: u64,
+    pub transactions: u64,
+    #[serde(default)]
+    pub sequence: u64, // the engine's sequence, which authorization expiries count from
+}
+
+// Tally counts the records that were accepted and rejected, the server sends it back at the end of each stream
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Tally {
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+// Trace writes a TraceRecord for every record, accepted or not
+pub struct Trace<W: Write> {
+    pub(crate) writer: Writer<W>,
+}
+
+// Statement writes a TraceRecord for every record that touches one client, with that client's balances after it
+// this is the trace cut down to a single client, for answering support questions about their history
+pub struct Statement<W: Write> {
+    pub(crate) writer: Writer<W>,
+    pub(crate) client: u16,
+}
+
+// FeeTier is one row of a fee schedule, withdrawals of at least `from` are charged `percent` of their amount
+// the percentage is read like an amount (at most four decimal places), so the fee never passes through a float either
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeTier {
+    pub from: Amount,
+    pub percent: Amount,
+}
+
+// FeeSchedule is the percentage charged on a withdrawal by the size of the withdrawal, each tier runs up to the next one's `from`
+// withdrawals smaller than the first tier aren't charged
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FeeSchedule {
+    pub(crate) tiers: Vec<FeeTier>, // sorted by from, no two the same
+}
+
+// FeeBatch writes a fee transaction for every accepted withdrawal, charged from the schedule, to be posted later from a trusted input
+// the fees get tx ids counting up from the first one we were given
+pub struct FeeBatch<W: Write> {
+    pub(crate) writer: Writer<W>,
+    pub(crate) schedule: FeeSchedule,
+    pub(crate) next_tx: Option<u32>, // None once we've run out of tx ids
+}
+
+// AuditRecord is one row of the audit report, every admin transaction whether it was accepted or not
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AuditRecord {
+    pub input: String,
+    pub record: u64,
+    pub client: u16,
+    pub tx: u32,
+    pub action: TransactionType,
+    #[serde(default, deserialize_with = "Amount::deserialize_signed_option")]
+    pub amount: Option<Amount>,
+    pub reason: Option<String>,
+    pub trusted: bool,
+    pub outcome: String, // e.g. unlocked, adjusted, or the rejection reason code
+}
+
+// AuditReport writes an AuditRecord for every unlock, lock and adjustment, so admin activity can be reviewed on its own
+pub struct AuditReport<W: Write> {
+    pub(crate) writer: Writer<W>,
+}
+
+// JournalEntry is one line of the journal, written for every record the engine saw whether it was accepted or not
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub sequence: u64, // counts up from 1 over every run that appended to the journal, a gap means an entry went missing
+    pub input: String,
+    pub record: u64,
+    pub row: String,
+    pub transaction: Option<RawTransaction>, // None if the record couldn't be read as a transaction
+    pub outcome: String, // e.g. deposited, or the rejection reason code, replaying the entry has to come out the same
+    pub detail: Option<String>, // human readable description of a rejection
+    #[serde(default)]
+    pub trusted: bool, // replayed through PaymentsEngine::apply_trusted if it was applied that way
+}
+
+// Journal appends a checksummed JournalEntry line for every record, replay_journal rebuilds the accounts from them
+pub struct Journal<W: Write> {
+    pub(crate) writer: BufWriter<W>,
+    pub(crate) sequence: u64, // the sequence number the next entry gets
 }
 
 // RawTransaction is read directly from the file then RawTransaction gets converted "Into" a Transaction
@@ -53,5 +496,11 @@ pub struct RawTransaction {
     pub transaction_type: TransactionType, 
     pub client: u16,
     pub tx: u32,
-    pub amount: Option<f64>, // we use an Option here since not all transaction types have an amount.
+    // read with a sign so adjustments can be negative, every other type is refused a negative amount when it becomes a Transaction
+    #[serde(default, deserialize_with = "Amount::deserialize_signed_option")]
+    pub amount: Option<Amount>, // we use an Option here since not all transaction types have an amount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>, // only admin transactions have one, so the column can be left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u16>, // only transfers have one, so the column can be left out too
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+
+// LOCAL
+use crate::components::data_structures::{PaymentsEngine, AccountOrder, RawClient, RawTransactionRecord, TransactionResponse, FraudLog};
+use crate::components::utilities::transaction_from_json;
+
+// how many threads answer requests, each one handles a request start to finish
+pub const HTTP_WORKERS: usize = 4;
+
+// where fraud warnings say a posted transaction came from
+const POST_INPUT: &str = "POST /transactions";
+
+// HttpServer serves the engine as JSON:
+//   POST /transactions                    one transaction or an array of them, shaped like RawTransaction
+//   GET  /accounts                        every account, shaped like RawClient
+//   GET  /accounts/{client}               one account
+//   GET  /accounts/{client}/transactions  the client's accepted deposits, withdrawals and transfers sent, and their dispute state
+pub struct HttpServer {
+    server: tiny_http::Server,
+    engine: Arc<Mutex<PaymentsEngine>>,
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(address: A, engine: Arc<Mutex<PaymentsEngine>>) -> Result<Self, Box<dyn Error>> {
+        // tiny_http's error is Send + Sync, which our Box<dyn Error> can't take directly
+        let server = tiny_http::Server::http(address).map_err(|err| err.to_string())?;
+        Ok(HttpServer { server, engine })
+    }
+    // the address we actually ended up on, useful when binding to port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+    // answer requests until the server shuts down
+    pub fn run(&self) {
+        thread::scope(|scope| {
+            for _ in 0..HTTP_WORKERS {
+                scope.spawn(|| {
+                    for request in self.server.incoming_requests() {
+                        if let Err(err) = handle_request(request, &self.engine) {
+                            eprintln!("[ERROR]: Could not answer an HTTP request: {}", err);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn handle_request(mut request: Request, engine: &Mutex<PaymentsEngine>) -> Result<(), Box<dyn Error>> {
+    let mut body = String::new();
+    let (status, json) = match request.as_reader().read_to_string(&mut body) {
+        Ok(_) => route(request.method(), request.url(), &body, engine)
+            .unwrap_or_else(|err| error_response(500, "internal_error", &err.to_string())),
+        Err(err) => error_response(400, "parse_error", &format!("[ERROR]: Could not read the request body: {}", err)),
+    };
+    let content_type = Header::from_bytes("Content-Type", "application/json").map_err(|_| "[ERROR]: Invalid header")?;
+    request.respond(Response::from_string(json.to_string()).with_status_code(status).with_header(content_type))?;
+    Ok(())
+}
+
+fn error_response(status: u16, reason: &str, detail: &str) -> (u16, Value) {
+    (status, json!({ "reason": reason, "detail": detail }))
+}
+
+// work out the status and JSON body for a request, kept apart from tiny_http so the routing reads on its own
+pub fn route(method: &Method, url: &str, body: &str, engine: &Mutex<PaymentsEngine>) -> Result<(u16, Value), Box<dyn Error>> {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (Method::Post, ["transactions"]) => post_transactions(body, engine),
+        (Method::Get, ["accounts"]) => {
+            let engine = lock(engine)?;
+            let accounts: Vec<RawClient> = engine.sorted_accounts(AccountOrder::ClientId).into_iter().map(|client| engine.raw_account(client)).collect();
+            Ok((200, serde_json::to_value(accounts)?))
+        },
+        (Method::Get, ["accounts", client]) => {
+            let client = match client.parse::<u16>() {
+                Ok(client) => client,
+                Err(_) => return Ok(invalid_client(client)),
+            };
+            let engine = lock(engine)?;
+            match engine.account(client) {
+                Some(account) => Ok((200, serde_json::to_value(engine.raw_account(account))?)),
+                None => Ok(unknown_client(client)),
+            }
+        },
+        (Method::Get, ["accounts", client, "transactions"]) => {
+            let client = match client.parse::<u16>() {
+                Ok(client) => client,
+                Err(_) => return Ok(invalid_client(client)),
+            };
+            let engine = lock(engine)?;
+            if engine.account(client).is_none() {
+                return Ok(unknown_client(client));
+            }
+            let transactions: Vec<RawTransactionRecord> = engine.client_transactions(client).into_iter().map(RawTransactionRecord::from).collect();
+            Ok((200, serde_json::to_value(transactions)?))
+        },
+        (_, ["transactions"]) | (_, ["accounts", ..]) => Ok(error_response(405, "method_not_allowed", &format!("[ERROR]: {} is not supported on {}", method, path))),
+        _ => Ok(error_response(404, "not_found", &format!("[ERROR]: Nothing lives at {}", path))),
+    }
+}
+
+fn lock(engine: &Mutex<PaymentsEngine>) -> Result<MutexGuard<'_, PaymentsEngine>, Box<dyn Error>> {
+    engine.lock().map_err(|_| "[ERROR]: Another thread panicked while applying a transaction, the accounts can't be trusted.".into())
+}
+
+fn invalid_client(client: &str) -> (u16, Value) {
+    error_response(400, "invalid_client", &format!("[ERROR]: '{}' is not a valid client id", client))
+}
+
+fn unknown_client(client: u16) -> (u16, Value) {
+    error_response(404, "unknown_client", &format!("[ERROR]: There is no account for client: {}", client))
+}
+
+// a single transaction gets 200 if it was accepted and 422 if it was rejected
+// a batch is applied in order and always gets 200, with a response per transaction saying how each one went
+// fraud warnings go to STDERR the same as for the other inputs, numbered by position in the batch
+fn post_transactions(body: &str, engine: &Mutex<PaymentsEngine>) -> Result<(u16, Value), Box<dyn Error>> {
+    let value: Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(err) => return Ok(error_response(400, "parse_error", &format!("[ERROR]: The request body is not valid JSON: {}", err))),
+    };
+    // the whole batch goes in under one lock, so nobody else's transactions land in the middle of it
+    let mut engine = lock(engine)?;
+    let mut fraud = FraudLog::new(io::stderr());
+    let mut apply = |record: u64, value: Value| -> Result<TransactionResponse, Box<dyn Error>> {
+        let transaction = transaction_from_json(value);
+        let result = match &transaction {
+            Ok(transaction) => engine.apply(transaction.clone()),
+            Err(error) => Err(error.clone()),
+        };
+        fraud.check(POST_INPUT, record, transaction.as_ref().ok(), &result, Some(&engine))?;
+        Ok(TransactionResponse::new(transaction.as_ref().ok(), &result))
+    };
+    match value {
+        Value::Array(values) => {
+            let responses = (1..).zip(values).map(|(record, value)| apply(record, value)).collect::<Result<Vec<TransactionResponse>, _>>()?;
+            Ok((200, serde_json::to_value(responses)?))
+        },
+        value => {
+            let response = apply(1, value)?;
+            let status = if response.accepted { 200 } else { 422 };
+            Ok((status, serde_json::to_value(response)?))
+        },
+    }
+}
@@ -1,90 +1,1129 @@
+use std::cmp::Reverse;
 use std::error::Error;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use csv::Writer;
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor};
 
 // LOCAL
-use crate::components::data_structures::{Client, Transaction, RawClient, RawTransaction};
+use crate::components::data_structures::{Amount, AmountError, Client, Transaction, RawClient, RawTransaction, PaymentsEngine, Outcome, EngineError, TransactionRecord, TxIdSet, TransactionType, DisputeState, DisputeEvent, RedisputePolicy, EngineConfig, AccountOrder, ProcessedRecord, RecordSink, RejectionReport, RejectedRecord, Strict, FraudLog, Trace, TraceRecord, OutputFormat, Tally, RawTransactionRecord, TransactionResponse, TxOwners, InputFormat, JournalEntry, Journal, Statement, StatementRecord, StatementSection, TXType, AuditRecord, AuditReport, FeeTier, FeeSchedule, FeeBatch, WithdrawalDisputePolicy, NegativeAvailablePolicy};
+use crate::components::utilities::{get_or_insert, transaction_handler, transfer_handler, expire_authorization};
+
+impl Amount {
+    // number of decimal places we keep, anything more precise than this is rejected when parsing
+    pub const SCALE: u32 = 4;
+    pub const ZERO: Amount = Amount(0);
+
+    // build an Amount from a whole number of ten-thousandths, e.g. Amount::from_units(15000) == 1.5
+    pub fn from_units(units: i64) -> Self {
+        Amount(units)
+    }
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+    pub fn checked_neg(self) -> Option<Amount> {
+        self.0.checked_neg().map(Amount)
+    }
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+    // the same as parsing with FromStr, except a negative value is allowed
+    // rust_decimal is more forgiving than we want (it takes "1_000" and rounds anything past 28 decimal places),
+    // so the text has to look like -?\d+(\.\d+)? and its precision is checked before rust_decimal sees it
+    pub fn parse_signed(text: &str) -> Result<Amount, AmountError> {
+        let trimmed = text.trim();
+        let unsigned = trimmed.strip_prefix('-').unwrap_or(trimmed);
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, "0"));
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+        if !is_digits(whole) || !is_digits(fraction) {
+            return Err(AmountError::Invalid(text.to_string()));
+        }
+        // trailing zeros don't count, so "1.50000" is still accepted as 1.5
+        if fraction.trim_end_matches('0').len() > Amount::SCALE as usize {
+            return Err(AmountError::TooPrecise(text.to_string()));
+        }
+        // the text is a plain decimal by now, so rust_decimal can only refuse it for being too big
+        let decimal = Decimal::from_str(trimmed)
+            .map_err(|_| AmountError::Overflow(text.to_string()))?
+            .normalize();
+        // shift the mantissa so that it is expressed in ten-thousandths
+        let units = 10i128.checked_pow(Amount::SCALE - decimal.scale())
+            .and_then(|factor| decimal.mantissa().checked_mul(factor))
+            .and_then(|units| i64::try_from(units).ok())
+            .ok_or_else(|| AmountError::Overflow(text.to_string()))?;
+        Ok(Amount(units))
+    }
+    // for the amounts an adjustment can give a sign, e.g. RawTransaction's
+    pub(crate) fn deserialize_signed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        Ok(SignedAmount::deserialize(deserializer)?.0)
+    }
+    pub(crate) fn deserialize_signed_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Amount>, D::Error> {
+        Ok(Option::<SignedAmount>::deserialize(deserializer)?.map(|signed| signed.0))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(text: &str) -> Result<Amount, AmountError> {
+        match Amount::parse_signed(text)? {
+            amount if amount.is_negative() => Err(AmountError::Negative(text.to_string())),
+            amount => Ok(amount),
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    // written with as few decimal places as possible (but at least one), e.g. 1.0, 1.5, 0.0001, -2.5
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let factor = 10u64.pow(Amount::SCALE);
+        let sign = if self.is_negative() { "-" } else { "" };
+        let whole = self.0.unsigned_abs() / factor;
+        let fraction = self.0.unsigned_abs() % factor;
+        match fraction {
+            0 => write!(f, "{}{}.0", sign, whole),
+            _ => {
+                let digits = format!("{:04}", fraction);
+                write!(f, "{}{}.{}", sign, whole, digits.trim_end_matches('0'))
+            },
+        }
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct AmountVisitor {
+    signed: bool,
+}
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.signed {
+            true => write!(f, "a decimal with at most {} decimal places", Amount::SCALE),
+            false => write!(f, "a non-negative decimal with at most {} decimal places", Amount::SCALE),
+        }
+    }
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Amount, E> {
+        match self.signed {
+            true => Amount::parse_signed(text).map_err(E::custom),
+            false => text.parse().map_err(E::custom),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    // we always ask for the raw text of the field, so the value never gets the chance to be read as an f64
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        deserializer.deserialize_str(AmountVisitor { signed: false })
+    }
+}
+
+// an Amount that may be negative, only used to read RawTransaction's amount
+struct SignedAmount(Amount);
+
+impl<'de> Deserialize<'de> for SignedAmount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SignedAmount, D::Error> {
+        deserializer.deserialize_str(AmountVisitor { signed: true }).map(SignedAmount)
+    }
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountError::Invalid(text) => write!(f, "'{}' is not a valid amount", text),
+            AmountError::Negative(text) => write!(f, "'{}' is negative, amounts must be zero or greater", text),
+            AmountError::TooPrecise(text) => write!(f, "'{}' has more than {} decimal places", text, Amount::SCALE),
+            AmountError::Overflow(text) => write!(f, "'{}' is too large to be represented as an amount", text),
+        }
+    }
+}
+
+impl Error for AmountError {}
+
+impl EngineError {
+    // short, stable identifier for each kind of rejection, used in the rejection report
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::AccountLocked { .. } => "account_locked",
+            EngineError::InsufficientFunds { .. } => "insufficient_funds",
+            EngineError::UnknownTransaction { .. } => "unknown_transaction",
+            EngineError::AlreadyDisputed { .. } => "already_disputed",
+            EngineError::NotDisputed { .. } => "not_disputed",
+            EngineError::AlreadySettled { .. } => "already_settled",
+            EngineError::MissingAmount { .. } => "missing_amount",
+            EngineError::Overflow { .. } => "overflow",
+            EngineError::ClientMismatch { .. } => "client_mismatch",
+            EngineError::DuplicateTransaction { .. } => "duplicate_transaction",
+            EngineError::Untrusted { .. } => "untrusted_source",
+            EngineError::NotDisputable { .. } => "not_disputable",
+            EngineError::InvalidDestination { .. } => "invalid_destination",
+            EngineError::ExceedsAuthorization { .. } => "exceeds_authorization",
+            EngineError::Parse(_) => "parse_error",
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::AccountLocked { client, tx } => write!(f, "[ERROR]: Client's (id: {}) account is frozen, further transactions are not allowed. Discarding transaction: {}.", client, tx),
+            EngineError::InsufficientFunds { client, tx, available, requested } => write!(f, "[ERROR]: Transaction: {}, for amount: {}, exceeds the available balance: {} for client: {}. Discarding transaction.", tx, requested, available, client),
+            EngineError::UnknownTransaction { client, tx } => write!(f, "[ERROR]: Could not find referenced transaction id: {} for client: {}. Discarding transaction.", tx, client),
+            EngineError::AlreadyDisputed { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, is already disputed, further disputes are not allowed. Discarding transaction.", tx, client),
+            EngineError::NotDisputed { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, isn't under dispute. Discarding transaction.", tx, client),
+            EngineError::AlreadySettled { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, is already resolved, further resolves and chargebacks are not allowed. Discarding transaction.", tx, client),
+            EngineError::MissingAmount { client, tx } => write!(f, "[ERROR]: Client id: {}, Transaction id: {}, requires an amount. Discarding transaction.", client, tx),
+            EngineError::Overflow { client, tx, amount } => write!(f, "[ERROR]: Transaction: {}, for amount: {}, will cause an overflowed (i64::MAX/10e3) balance for client: {}. Discarding transaction.", tx, amount, client),
+            EngineError::ClientMismatch { client, tx, owner } => write!(f, "[ERROR]: Transaction id: {} belongs to client: {}, not client: {}. Discarding transaction.", tx, owner, client),
+            EngineError::DuplicateTransaction { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, has already been used, tx ids must be unique. Discarding transaction.", tx, client),
+            EngineError::Untrusted { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, is an admin transaction, fee or interest and can only come from a trusted source. Discarding transaction.", tx, client),
+            EngineError::NotDisputable { client, tx } => write!(f, "[ERROR]: Transaction id: {}, for client: {}, can't be disputed, fees, interest, adjustments and authorizations never can be and neither can withdrawals under the reject policy. Discarding transaction.", tx, client),
+            EngineError::InvalidDestination { client, tx } => write!(f, "[ERROR]: Transfer id: {}, from client: {}, needs a to client other than the sender. Discarding transaction.", tx, client),
+            EngineError::ExceedsAuthorization { client, tx, authorized, requested } => write!(f, "[ERROR]: Capture of authorization id: {}, for amount: {}, exceeds the authorized amount: {} for client: {}. Discarding transaction.", tx, requested, authorized, client),
+            EngineError::Parse(message) => write!(f, "[ERROR]: Could not read transaction: {}. Discarding transaction.", message),
+        }
+    }
+}
+
+impl Error for EngineError {}
+
+impl PaymentsEngine {
+    pub fn new() -> Self {
+        PaymentsEngine::default()
+    }
+    pub fn with_config(config: EngineConfig) -> Self {
+        PaymentsEngine {
+            config,
+            ..PaymentsEngine::default()
+        }
+    }
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+    // apply a single transaction, creating the client's account if this is the first we've heard of them
+    // admin transactions are refused, they have to go through apply_trusted
+    pub fn apply(&mut self, transaction: Transaction) -> Result<Outcome, EngineError> {
+        self.apply_from(transaction, false)
+    }
+    // apply a transaction from a trusted source (e.g. an operator's file), the only way unlock, lock and adjustment are accepted
+    pub fn apply_trusted(&mut self, transaction: Transaction) -> Result<Outcome, EngineError> {
+        self.apply_from(transaction, true)
+    }
+    fn apply_from(&mut self, transaction: Transaction, trusted: bool) -> Result<Outcome, EngineError> {
+        // refused before the account is opened, an untrusted source shouldn't be able to create accounts with admin transactions either
+        if transaction.transaction_type.requires_trust() && !trusted {
+            return Err(EngineError::Untrusted { client: transaction.client, tx: transaction.tx });
+        }
+        // every transaction that gets this far counts towards expiring authorizations, whether or not it's accepted
+        self.sequence += 1;
+        self.expire_authorizations();
+        // tx ids are globally unique, so anything that moves funds reusing one we've already accepted is a replay
+        let creates_record = matches!(transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer | TransactionType::Fee | TransactionType::Interest | TransactionType::Authorize | TransactionType::Adjustment);
+        if creates_record && self.transactions.contains_key(&transaction.tx) {
+            return Err(EngineError::DuplicateTransaction { client: transaction.client, tx: transaction.tx });
+        }
+        // a transfer, or a dispute of one, changes two accounts so it can't go through the single client handlers
+        let involves_transfer = match transaction.transaction_type {
+            TransactionType::Transfer => true,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => self.transactions.get(&transaction.tx).is_some_and(|record| record.transaction_type == TransactionType::Transfer),
+            _ => false,
+        };
+        // the recipient's account is only opened if the transfer goes through
+        let new_recipient = transaction.to.filter(|to| involves_transfer && !self.clients.contains_key(to));
+        if !self.clients.contains_key(&transaction.client) {
+            self.first_seen.push(transaction.client);
+        }
+        let client = get_or_insert(transaction.client, &mut self.clients);
+        let outcome = match involves_transfer {
+            true => transfer_handler(&mut self.clients, &mut self.transactions, &self.config, &transaction)?,
+            false => transaction_handler(client, &mut self.transactions, &self.config, &transaction)?,
+        };
+        self.first_seen.extend(new_recipient);
+        if let (Outcome::Authorized(_), Some(window)) = (outcome, self.config.authorization_expiry) {
+            let expires = self.sequence.saturating_add(window);
+            if let Some(record) = self.transactions.get_mut(&transaction.tx) {
+                record.expires = Some(expires);
+            }
+            self.expiries.push(Reverse((expires, transaction.tx)));
+        }
+        Ok(outcome)
+    }
+    // release every authorization whose window has passed before the transaction at self.sequence, captured and voided ones are skipped
+    fn expire_authorizations(&mut self) {
+        while let Some(Reverse((expires, tx))) = self.expiries.peek().copied() {
+            if expires >= self.sequence {
+                break;
+            }
+            self.expiries.pop();
+            // nothing is released if it was settled already, or if available has since grown so close to the limit that the funds can't fit back,
+            // in which case they stay held until the authorization is voided or captured
+            expire_authorization(&mut self.clients, &mut self.transactions, &self.config, tx);
+        }
+    }
+    // how many transactions the engine has applied, what authorization expiries count in
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    // put engines that each processed a disjoint set of clients back together, e.g. the shards of process_parallel
+    // first_seen is the client order across all of them, which no single shard knows
+    pub fn merge(shards: Vec<PaymentsEngine>, config: EngineConfig, first_seen: Vec<u16>) -> Self {
+        let mut merged = PaymentsEngine::with_config(config);
+        for shard in shards {
+            merged.clients.extend(shard.clients);
+            merged.transactions.extend(shard.transactions);
+            // each shard counted only the transactions it applied, between them they count them all
+            merged.sequence += shard.sequence;
+        }
+        merged.first_seen = first_seen;
+        merged
+    }
+    // hand each of `shards` engines the clients whose id % shards is theirs, along with those clients' transactions
+    pub fn split(self, shards: usize) -> Vec<PaymentsEngine> {
+        let mut engines: Vec<PaymentsEngine> = (0..shards).map(|_| PaymentsEngine::with_config(self.config)).collect();
+        // the sequence so far rides along with the first shard so merge adds it back
+        engines[0].sequence = self.sequence;
+        for (id, client) in self.clients {
+            engines[id as usize % shards].clients.insert(id, client);
+        }
+        for (tx, record) in self.transactions {
+            engines[record.client as usize % shards].transactions.insert(tx, record);
+        }
+        engines
+    }
+    // start tracking a client without applying anything, used when a transaction was refused before it got to this engine
+    pub(crate) fn open_account(&mut self, client_id: u16) {
+        get_or_insert(client_id, &mut self.clients);
+    }
+    // look up a deposit or withdrawal the engine has accepted
+    pub fn transaction(&self, tx: u32) -> Option<&TransactionRecord> {
+        self.transactions.get(&tx)
+    }
+    // every deposit, withdrawal and transfer accepted for a client, by tx id (a transfer is listed under its sender)
+    pub fn client_transactions(&self, client_id: u16) -> Vec<(u32, &TransactionRecord)> {
+        let mut transactions: Vec<(u32, &TransactionRecord)> = self.transactions.iter()
+            .filter(|(_, record)| record.client == client_id)
+            .map(|(tx, record)| (*tx, record))
+            .collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        transactions
+    }
+    pub fn account(&self, client_id: u16) -> Option<&Client> {
+        self.clients.get(&client_id)
+    }
+    // every account in no particular order, see sorted_accounts for a stable one
+    pub fn accounts(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values()
+    }
+    pub fn sorted_accounts(&self, order: AccountOrder) -> Vec<&Client> {
+        let mut accounts: Vec<&Client> = match order {
+            AccountOrder::FirstSeen => return self.first_seen.iter().filter_map(|id| self.clients.get(id)).collect(),
+            _ => self.clients.values().collect(),
+        };
+        match order {
+            AccountOrder::Total => accounts.sort_by_key(|client| (Reverse(client.total), client.client)),
+            AccountOrder::Available => accounts.sort_by_key(|client| (Reverse(client.available), client.client)),
+            _ => accounts.sort_by_key(|client| client.client),
+        }
+        accounts
+    }
+    // an account the way it's written out, nobody can be flagged under NegativeAvailablePolicy::Forbid so the column is left out there
+    pub fn raw_account(&self, client: &Client) -> RawClient {
+        let mut raw = RawClient::from(client);
+        if self.config.negative_available == NegativeAvailablePolicy::Forbid {
+            raw.flagged = None;
+        }
+        raw
+    }
+}
 
 impl Client {
     pub fn new(id: u16) -> Self {
         Client {
             client: id,
-            available: 0,
-            held: 0,
-            total: 0,
-            transactions: Vec::new(),
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
+            flagged: false,
         }
     }
-    pub fn is_frozen(&self) -> Result<(), Box<dyn Error>> {
+    pub fn is_frozen(&self, tx: u32) -> Result<(), EngineError> {
         match self.locked {
             false => Ok(()),
-            true => Err(format!("[ERROR]: Client's (id: {}) account is frozen, further transactions are not allowed.", self.client).into()),
+            true => Err(EngineError::AccountLocked { client: self.client, tx }),
         }
     }
 }
 
-impl Transaction {
-    pub fn is_already_disputed(&self) -> Result<(), Box<dyn Error>> {
-        match self.disputed {
-            false => Ok(()),
-            // taking care of edge case with multiple disputes
-            true => Err(format!("[ERROR]: Transaction id: {}, is already disputed, further disputes are not allowed. Discarding transaction.", self.tx).into()),
+impl Outcome {
+    // short, stable name for what happened, the accepted counterpart to EngineError::code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Outcome::Deposited(_) => "deposited",
+            Outcome::Withdrawn(_) => "withdrawn",
+            Outcome::Disputed(_) => "disputed",
+            Outcome::Resolved(_) => "resolved",
+            Outcome::ChargedBack(_) => "charged_back",
+            Outcome::Unlocked => "unlocked",
+            Outcome::Locked => "locked",
+            Outcome::Adjusted(_) => "adjusted",
+            Outcome::Transferred(_) => "transferred",
+            Outcome::FeeCharged(_) => "fee_charged",
+            Outcome::InterestPaid(_) => "interest_paid",
+            Outcome::Authorized(_) => "authorized",
+            Outcome::Captured(_) => "captured",
+            Outcome::Voided(_) => "voided",
         }
     }
-    pub fn is_already_resolved(&self) -> Result<(), Box<dyn Error>> {
-        match self.resolved {
-            false => Ok(()),
-            // taking care of edge case with multiple resolves
-            true => Err(format!("[ERROR]: Transaction id: {}, is already resolved, further resolves and chargebacks are not allowed. Discarding transaction.", self.tx).into()),
+    // locking and unlocking don't move any funds
+    pub fn amount(&self) -> Amount {
+        match self {
+            Outcome::Deposited(amount) | Outcome::Withdrawn(amount) | Outcome::Disputed(amount) | Outcome::Resolved(amount) | Outcome::ChargedBack(amount) | Outcome::Adjusted(amount) | Outcome::Transferred(amount) | Outcome::FeeCharged(amount) | Outcome::InterestPaid(amount) | Outcome::Authorized(amount) | Outcome::Captured(amount) | Outcome::Voided(amount) => *amount,
+            Outcome::Unlocked | Outcome::Locked => Amount::ZERO,
+        }
+    }
+}
+
+impl ProcessedRecord<'_> {
+    // the record as it was read, joined back into a single csv row
+    pub fn row_text(&self) -> String {
+        self.row.iter().collect::<Vec<&str>>().join(",")
+    }
+}
+
+// the unit type is the sink to use when nobody needs to hear about individual records
+impl RecordSink for () {
+    fn record(&mut self, _processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// several sinks can be combined, each one sees every record in order
+impl RecordSink for Vec<Box<dyn RecordSink>> {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        for sink in self.iter_mut() {
+            sink.record(processed, engine)?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        for sink in self.iter_mut() {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> RejectionReport<W> {
+    pub fn new(destination: W) -> Self {
+        RejectionReport { writer: Writer::from_writer(destination) }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for RejectionReport<W> {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        if let Err(error) = processed.result {
+            self.writer.serialize(RejectedRecord {
+                input: processed.input.to_string(),
+                record: processed.record,
+                row: processed.row_text(),
+                reason: error.code().to_string(),
+                detail: error.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl RecordSink for Strict {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        match processed.result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("[ERROR]: Stopping at {} record #{} ({}) in strict mode: {}", processed.input, processed.record, processed.row_text(), error).into()),
+        }
+    }
+}
+
+impl<W: Write> FraudLog<W> {
+    pub fn new(destination: W) -> Self {
+        FraudLog { writer: destination }
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    // warn about the result of record number `record` from input, for when the record didn't come through a read loop (e.g. the HTTP API)
+    // engine is the one the transaction was applied to, the parallel reader has none and only ever catches client mismatches
+    pub fn check(&mut self, input: &str, record: u64, transaction: Option<&Transaction>, result: &Result<Outcome, EngineError>, engine: Option<&PaymentsEngine>) -> Result<(), Box<dyn Error>> {
+        if let Err(EngineError::ClientMismatch { client, tx, owner }) = result {
+            writeln!(self.writer, "[WARNING]: Possible fraud at {} record #{}: client {} referenced tx {}, which belongs to client {}.", input, record, client, tx, owner)?;
+        }
+        // a dispute taking available below zero means the funds were spent first, for a transfer it's the recipient who spent them
+        if let (Some(transaction), Ok(Outcome::Disputed(_) | Outcome::ChargedBack(_)), Some(engine)) = (transaction, result, engine) {
+            let holder = engine.transaction(transaction.tx).and_then(|record| record.to).unwrap_or(transaction.client);
+            if engine.account(holder).is_some_and(|client| client.available.is_negative()) {
+                writeln!(self.writer, "[WARNING]: Client {} is flagged for review at {} record #{}: their available balance is negative under a dispute of tx {}.", holder, input, record, transaction.tx)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordSink for FraudLog<W> {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        self.check(processed.input, processed.record, processed.transaction, processed.result, Some(engine))
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// a pair of sinks, for when the caller needs to get one of them back afterwards (a Vec of boxes can't hand them back)
+impl<A: RecordSink, B: RecordSink> RecordSink for (A, B) {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        self.0.record(processed, engine)?;
+        self.1.record(processed, engine)
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.0.finish()?;
+        self.1.finish()
+    }
+}
+
+impl TransactionResponse {
+    pub fn new(transaction: Option<&Transaction>, result: &Result<Outcome, EngineError>) -> Self {
+        TransactionResponse {
+            tx: transaction.map(|transaction| transaction.tx),
+            client: transaction.map(|transaction| transaction.client),
+            accepted: result.is_ok(),
+            outcome: result.as_ref().ok().map(|outcome| outcome.code().to_string()),
+            amount: result.as_ref().ok().map(|outcome| outcome.amount()),
+            reason: result.as_ref().err().map(|error| error.code().to_string()),
+            detail: result.as_ref().err().map(|error| error.to_string()),
+        }
+    }
+}
+
+impl RecordSink for Tally {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        match processed.result {
+            Ok(_) => self.accepted += 1,
+            Err(_) => self.rejected += 1,
         }
+        Ok(())
     }
 }
 
-impl From<RawTransaction> for Transaction { // with a From implementation, we automatically get an Into implementation
-    fn from(raw_tx: RawTransaction) -> Transaction {
-        match raw_tx.amount {
-            Some(actual_amount) => {
-                Transaction {
-                    transaction_type: raw_tx.transaction_type,
-                    client: raw_tx.client,
-                    tx: raw_tx.tx,
-                    disputed: false,
-                    resolved: false,
-                    amount: Some((actual_amount * 10000.0) as u64),
-                }
+impl<W: Write> Trace<W> {
+    pub fn new(destination: W) -> Self {
+        Trace { writer: Writer::from_writer(destination) }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for Trace<W> {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        let client = processed.transaction.and_then(|tx| engine.account(tx.client));
+        self.writer.serialize(TraceRecord::new(processed, client))?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl TraceRecord {
+    // what happened to a record, along with the balances of the account we're following
+    pub fn new(processed: &ProcessedRecord, client: Option<&Client>) -> Self {
+        TraceRecord {
+            input: processed.input.to_string(),
+            record: processed.record,
+            row: processed.row_text(),
+            outcome: match processed.result {
+                Ok(outcome) => outcome.code().to_string(),
+                Err(error) => error.code().to_string(),
             },
-            None => {
-                Transaction {
-                    transaction_type: raw_tx.transaction_type,
-                    client: raw_tx.client,
-                    tx: raw_tx.tx,
-                    disputed: false,
-                    resolved: false,
-                    amount: None,
-                }
+            amount: processed.result.as_ref().ok().map(|outcome| outcome.amount()),
+            available: client.map(|client| client.available),
+            held: client.map(|client| client.held),
+            total: client.map(|client| client.total),
+            locked: client.map(|client| client.locked),
+        }
+    }
+}
+
+impl<W: Write> Statement<W> {
+    pub fn new(destination: W, client: u16) -> Self {
+        Statement { writer: Writer::from_writer(destination), client }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for Statement<W> {
+    fn record(&mut self, processed: &ProcessedRecord, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        // the client's own records, transfers to them (and disputes of those), and anyone else reaching for one of the client's transactions
+        let touches_client = match (processed.transaction, processed.result) {
+            (Some(transaction), _) if transaction.client == self.client => true,
+            (Some(transaction), _) if transaction.to == Some(self.client) => true,
+            (Some(transaction), Ok(Outcome::Disputed(_) | Outcome::Resolved(_) | Outcome::ChargedBack(_))) => engine.transaction(transaction.tx).is_some_and(|record| record.to == Some(self.client)),
+            (_, Err(EngineError::ClientMismatch { owner, .. })) => *owner == self.client,
+            _ => false,
+        };
+        if touches_client {
+            // fees and interest are the platform's doing, so they're kept apart from the client's own activity
+            let section = match processed.transaction.map(|transaction| transaction.transaction_type) {
+                Some(TXType::Fee | TXType::Interest) => StatementSection::FeesAndInterest,
+                _ => StatementSection::Activity,
+            };
+            self.writer.serialize(StatementRecord::new(section, TraceRecord::new(processed, engine.account(self.client))))?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl StatementRecord {
+    pub fn new(section: StatementSection, trace: TraceRecord) -> Self {
+        StatementRecord {
+            section,
+            input: trace.input,
+            record: trace.record,
+            row: trace.row,
+            outcome: trace.outcome,
+            amount: trace.amount,
+            available: trace.available,
+            held: trace.held,
+            total: trace.total,
+            locked: trace.locked,
+        }
+    }
+}
+
+impl<W: Write> Journal<W> {
+    // sequence is the number the first entry written gets, 1 for a new journal
+    pub fn new(destination: W, sequence: u64) -> Self {
+        Journal { writer: BufWriter::new(destination), sequence }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for Journal<W> {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        let entry = JournalEntry {
+            sequence: self.sequence,
+            input: processed.input.to_string(),
+            record: processed.record,
+            row: processed.row_text(),
+            transaction: processed.transaction.cloned().map(RawTransaction::from),
+            outcome: match processed.result {
+                Ok(outcome) => outcome.code().to_string(),
+                Err(error) => error.code().to_string(),
+            },
+            detail: processed.result.as_ref().err().map(|error| error.to_string()),
+            trusted: processed.trusted,
+        };
+        // each line is the crc32 of the entry's JSON in hex followed by the JSON itself, so a torn or edited line can't slip through
+        let json = serde_json::to_string(&entry)?;
+        writeln!(self.writer, "{:08x} {}", crc32fast::hash(json.as_bytes()), json)?;
+        self.sequence += 1;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> AuditReport<W> {
+    pub fn new(destination: W) -> Self {
+        AuditReport { writer: Writer::from_writer(destination) }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for AuditReport<W> {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        let Some(transaction) = processed.transaction.filter(|transaction| transaction.transaction_type.is_admin()) else {
+            return Ok(());
+        };
+        self.writer.serialize(AuditRecord {
+            input: processed.input.to_string(),
+            record: processed.record,
+            client: transaction.client,
+            tx: transaction.tx,
+            action: transaction.transaction_type,
+            amount: transaction.amount,
+            reason: transaction.reason.clone(),
+            trusted: processed.trusted,
+            outcome: match processed.result {
+                Ok(outcome) => outcome.code().to_string(),
+                Err(error) => error.code().to_string(),
+            },
+        })?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl TXType {
+    // unlock, lock and adjustment are operator actions rather than client payments
+    pub fn is_admin(self) -> bool {
+        matches!(self, TXType::Unlock | TXType::Lock | TXType::Adjustment)
+    }
+    // fees and interest are posted by the platform, a client can't charge or pay themselves
+    pub fn is_posting(self) -> bool {
+        matches!(self, TXType::Fee | TXType::Interest)
+    }
+    // only accepted through PaymentsEngine::apply_trusted
+    pub fn requires_trust(self) -> bool {
+        self.is_admin() || self.is_posting()
+    }
+}
+
+impl FeeSchedule {
+    // the tiers can be given in any order, but each one has to start at a different amount and charge at most 100%
+    pub fn new(mut tiers: Vec<FeeTier>) -> Result<FeeSchedule, String> {
+        tiers.sort_by_key(|tier| tier.from);
+        if let Some(tier) = tiers.iter().find(|tier| tier.percent > Amount::from_units(100 * 10i64.pow(Amount::SCALE))) {
+            return Err(format!("[ERROR]: The fee tier from {} charges {}%, a fee can't be more than 100% of the withdrawal.", tier.from, tier.percent));
+        }
+        if let Some(pair) = tiers.windows(2).find(|pair| pair[0].from == pair[1].from) {
+            return Err(format!("[ERROR]: The fee schedule has more than one tier from {}.", pair[0].from));
+        }
+        Ok(FeeSchedule { tiers })
+    }
+    pub fn tiers(&self) -> &[FeeTier] {
+        &self.tiers
+    }
+    // the fee on a withdrawal of amount, rounded to the nearest ten-thousandth (halves round up)
+    pub fn fee(&self, amount: Amount) -> Amount {
+        let Some(tier) = self.tiers.iter().rev().find(|tier| tier.from <= amount) else {
+            return Amount::ZERO;
+        };
+        // both are in ten-thousandths, so the product is divided by 100% expressed in ten-thousandths as well
+        let divisor = 100 * 10i128.pow(Amount::SCALE);
+        let units = (amount.0 as i128 * tier.percent.0 as i128 + divisor / 2) / divisor;
+        // at most 100% of the withdrawal, so this always fits back into an i64
+        Amount(units as i64)
+    }
+}
+
+impl<W: Write> FeeBatch<W> {
+    // first_tx is the tx id the first fee gets, it has to be clear of every id the fees will be posted alongside
+    pub fn new(destination: W, schedule: FeeSchedule, first_tx: u32) -> Self {
+        FeeBatch { writer: Writer::from_writer(destination), schedule, next_tx: Some(first_tx) }
+    }
+    pub fn into_inner(self) -> Result<W, Box<dyn Error>> {
+        self.writer.into_inner().map_err(|err| err.to_string().into())
+    }
+}
+
+impl<W: Write> RecordSink for FeeBatch<W> {
+    fn record(&mut self, processed: &ProcessedRecord, _engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+        let (Some(withdrawal), Ok(Outcome::Withdrawn(amount))) = (processed.transaction, processed.result) else {
+            return Ok(());
+        };
+        let fee = self.schedule.fee(*amount);
+        if fee == Amount::ZERO {
+            return Ok(());
+        }
+        let tx = self.next_tx.ok_or("[ERROR]: Ran out of tx ids for fees, start them from a lower --first-tx.")?;
+        self.writer.serialize(RawTransaction {
+            transaction_type: TXType::Fee,
+            client: withdrawal.client,
+            tx,
+            amount: Some(fee),
+            reason: Some(format!("fee on withdrawal {}", withdrawal.tx)),
+            to: None,
+        })?;
+        self.next_tx = tx.checked_add(1);
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl FromStr for AccountOrder {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<AccountOrder, String> {
+        match text {
+            "client" => Ok(AccountOrder::ClientId),
+            "total" => Ok(AccountOrder::Total),
+            "available" => Ok(AccountOrder::Available),
+            "first-seen" => Ok(AccountOrder::FirstSeen),
+            _ => Err(format!("unknown account order '{}', expected one of: client, total, available, first-seen", text)),
+        }
+    }
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<WithdrawalDisputePolicy, String> {
+        match text {
+            "reject" => Ok(WithdrawalDisputePolicy::Reject),
+            "hold" => Ok(WithdrawalDisputePolicy::HoldAsRefund),
+            "reverse" => Ok(WithdrawalDisputePolicy::ReverseImmediately),
+            _ => Err(format!("unknown withdrawal dispute policy '{}', expected one of: reject, hold, reverse", text)),
+        }
+    }
+}
+
+impl FromStr for RedisputePolicy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<RedisputePolicy, String> {
+        match text {
+            "forbid" => Ok(RedisputePolicy::Forbid),
+            "allow" => Ok(RedisputePolicy::Allow),
+            _ => Err(format!("unknown redispute policy '{}', expected one of: forbid, allow", text)),
+        }
+    }
+}
+
+impl FromStr for NegativeAvailablePolicy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<NegativeAvailablePolicy, String> {
+        match text {
+            "forbid" => Ok(NegativeAvailablePolicy::Forbid),
+            "allow" => Ok(NegativeAvailablePolicy::AllowUnderDispute),
+            _ => Err(format!("unknown negative available policy '{}', expected one of: forbid, allow", text)),
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<InputFormat, String> {
+        match text {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            _ => Err(format!("unknown input format '{}', expected one of: csv, jsonl", text)),
+        }
+    }
+}
+
+impl InputFormat {
+    // guess the format from a file's extension, anything we don't recognise (including STDIN) is csv
+    pub fn from_path(path: &OsStr) -> InputFormat {
+        match extension(path).as_deref() {
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<OutputFormat, String> {
+        match text {
+            "csv" => Ok(OutputFormat::Csv),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format '{}', expected one of: csv, jsonl, json", text)),
+        }
+    }
+}
+
+impl OutputFormat {
+    // guess the format from a file's extension, anything we don't recognise is csv
+    pub fn from_path(path: &OsStr) -> OutputFormat {
+        match extension(path).as_deref() {
+            Some("jsonl") | Some("ndjson") => OutputFormat::Jsonl,
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Csv,
+        }
+    }
+}
+
+fn extension(path: &OsStr) -> Option<String> {
+    Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+impl TxIdSet {
+    const PAGE_BITS: u32 = 16;
+    const WORDS_PER_PAGE: usize = (1 << TxIdSet::PAGE_BITS) / 64;
+
+    pub fn new() -> Self {
+        TxIdSet::default()
+    }
+    // split a tx id into (page, word within the page, bit within the word)
+    fn locate(tx: u32) -> (usize, usize, u64) {
+        let page = (tx >> TxIdSet::PAGE_BITS) as usize;
+        let offset = (tx & ((1 << TxIdSet::PAGE_BITS) - 1)) as usize;
+        (page, offset / 64, 1 << (offset % 64))
+    }
+    pub fn contains(&self, tx: u32) -> bool {
+        let (page, word, bit) = TxIdSet::locate(tx);
+        match self.pages.get(page) {
+            Some(Some(words)) => words[word] & bit != 0,
+            _ => false,
+        }
+    }
+    // returns true if the id wasn't already in the set
+    pub fn insert(&mut self, tx: u32) -> bool {
+        let (page, word, bit) = TxIdSet::locate(tx);
+        if self.pages.len() <= page {
+            self.pages.resize(page + 1, None);
+        }
+        let words = self.pages[page].get_or_insert_with(|| vec![0; TxIdSet::WORDS_PER_PAGE].into_boxed_slice());
+        let is_new = words[word] & bit == 0;
+        words[word] |= bit;
+        if is_new {
+            self.len += 1;
+        }
+        is_new
+    }
+    // returns true if the id was in the set
+    pub fn remove(&mut self, tx: u32) -> bool {
+        let (page, word, bit) = TxIdSet::locate(tx);
+        let Some(Some(words)) = self.pages.get_mut(page) else { return false };
+        let was_in = words[word] & bit != 0;
+        words[word] &= !bit;
+        if was_in {
+            self.len -= 1;
+        }
+        was_in
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    // add every id in other to this set
+    pub fn union(&mut self, other: TxIdSet) {
+        if self.pages.len() < other.pages.len() {
+            self.pages.resize(other.pages.len(), None);
+        }
+        for (page, other_page) in other.pages.into_iter().enumerate() {
+            let Some(other_words) = other_page else { continue };
+            match &mut self.pages[page] {
+                Some(words) => {
+                    for (word, other_word) in words.iter_mut().zip(other_words.iter()) {
+                        self.len += (other_word & !*word).count_ones() as usize;
+                        *word |= other_word;
+                    }
+                },
+                empty => {
+                    self.len += other_words.iter().map(|word| word.count_ones() as usize).sum::<usize>();
+                    *empty = Some(other_words);
+                },
             }
         }
     }
 }
 
+impl TxOwners {
+    pub fn new() -> Self {
+        TxOwners::default()
+    }
+    // the client whose claim on tx was accepted
+    pub fn owner(&self, tx: u32) -> Option<u16> {
+        match self.claimed.contains(tx) && !self.pending.contains(tx) {
+            true => self.slot(tx),
+            false => None,
+        }
+    }
+    // the client whose claim on tx is still waiting to be accepted or refused
+    pub fn claimant(&self, tx: u32) -> Option<u16> {
+        match self.pending.contains(tx) {
+            true => self.slot(tx),
+            false => None,
+        }
+    }
+    // claim tx for client unless someone already has it, returns the owner either way
+    pub fn claim(&mut self, tx: u32, client: u16) -> u16 {
+        if let Some(owner) = self.owner(tx) {
+            return owner;
+        }
+        self.set_slot(tx, client);
+        self.claimed.insert(tx);
+        client
+    }
+    // put in a claim for client that only takes hold once settle accepts it, the id must be free or already pending for the same client
+    pub fn propose(&mut self, tx: u32, client: u16) {
+        if self.pending.contains(tx) {
+            *self.repeats.entry(tx).or_default() += 1;
+            return;
+        }
+        self.set_slot(tx, client);
+        self.claimed.insert(tx);
+        self.pending.insert(tx);
+    }
+    // a shard accepted or refused the earliest outstanding claim on tx, once one is accepted the rest are duplicates it already refused
+    pub fn settle(&mut self, tx: u32, accepted: bool) {
+        if !self.pending.contains(tx) {
+            return;
+        }
+        let repeats = self.repeats.remove(&tx).unwrap_or(0);
+        if accepted {
+            self.pending.remove(tx);
+        } else if repeats > 0 {
+            self.repeats.insert(tx, repeats - 1);
+        } else {
+            self.pending.remove(tx);
+            self.claimed.remove(tx);
+        }
+    }
+    fn slot(&self, tx: u32) -> Option<u16> {
+        let (page, offset) = TxOwners::locate(tx);
+        self.pages[page].as_ref().map(|owners| owners[offset])
+    }
+    fn set_slot(&mut self, tx: u32, client: u16) {
+        let (page, offset) = TxOwners::locate(tx);
+        if self.pages.len() <= page {
+            self.pages.resize(page + 1, None);
+        }
+        self.pages[page].get_or_insert_with(|| vec![0; 1 << TxIdSet::PAGE_BITS].into_boxed_slice())[offset] = client;
+    }
+    // split a tx id into (page, offset within the page), the pages line up with TxIdSet's
+    fn locate(tx: u32) -> (usize, usize) {
+        ((tx >> TxIdSet::PAGE_BITS) as usize, (tx & ((1 << TxIdSet::PAGE_BITS) - 1)) as usize)
+    }
+}
+
+impl TransactionRecord {
+    // remember an accepted deposit, withdrawal or transfer, it starts out undisputed
+    pub fn new(tx: &Transaction, amount: Amount) -> Self {
+        TransactionRecord {
+            transaction_type: tx.transaction_type,
+            client: tx.client,
+            amount,
+            state: DisputeState::Processed,
+            to: tx.to.filter(|_| tx.transaction_type == TransactionType::Transfer), // only a transfer has a recipient
+            expires: None,
+            dispute_policy: None,
+        }
+    }
+    // work out the state this record would move to, without changing anything
+    pub fn next_state(&self, event: DisputeEvent, policy: RedisputePolicy, tx: u32) -> Result<DisputeState, EngineError> {
+        self.state.transition(event, policy, self.client, tx)
+    }
+}
+
+impl DisputeState {
+    // the one place that decides which moves through the dispute lifecycle are legal
+    pub fn transition(self, event: DisputeEvent, policy: RedisputePolicy, client: u16, tx: u32) -> Result<DisputeState, EngineError> {
+        match (self, event) {
+            (DisputeState::Processed, DisputeEvent::Dispute) => Ok(DisputeState::Disputed),
+            (DisputeState::Processed, _) => Err(EngineError::NotDisputed { client, tx }),
+            // taking care of edge case with multiple disputes
+            (DisputeState::Disputed, DisputeEvent::Dispute) => Err(EngineError::AlreadyDisputed { client, tx }),
+            (DisputeState::Disputed, DisputeEvent::Resolve) => Ok(DisputeState::Resolved),
+            (DisputeState::Disputed, DisputeEvent::Chargeback) => Ok(DisputeState::ChargedBack),
+            (DisputeState::Resolved, DisputeEvent::Dispute) if policy == RedisputePolicy::Allow => Ok(DisputeState::Disputed),
+            // taking care of edge case with multiple resolves, and chargebacks after a resolve
+            (DisputeState::Resolved, _) | (DisputeState::ChargedBack, _) => Err(EngineError::AlreadySettled { client, tx }),
+        }
+    }
+}
+
+impl From<&RawTransactionRecord> for TransactionRecord {
+    fn from(raw: &RawTransactionRecord) -> Self {
+        TransactionRecord {
+            transaction_type: raw.transaction_type,
+            client: raw.client,
+            amount: raw.amount,
+            state: raw.state,
+            to: raw.to,
+            expires: raw.expires,
+            dispute_policy: raw.dispute_policy,
+        }
+    }
+}
+
+impl From<(u32, &TransactionRecord)> for RawTransactionRecord {
+    fn from((tx, record): (u32, &TransactionRecord)) -> Self {
+        RawTransactionRecord {
+            tx,
+            transaction_type: record.transaction_type,
+            client: record.client,
+            amount: record.amount,
+            state: record.state,
+            to: record.to,
+            expires: record.expires,
+            dispute_policy: record.dispute_policy,
+        }
+    }
+}
+
+impl TryFrom<RawTransaction> for Transaction { // with a TryFrom implementation, we automatically get a TryInto implementation
+    type Error = EngineError;
+
+    // only an adjustment can take funds away, so any other transaction with a negative amount can't be read
+    // and only a transfer goes to someone, a recipient on anything else would end up in the index (and in snapshots) as if it were one
+    fn try_from(raw_tx: RawTransaction) -> Result<Transaction, EngineError> {
+        match (raw_tx.amount, raw_tx.to) {
+            (Some(amount), _) if amount.is_negative() && raw_tx.transaction_type != TransactionType::Adjustment => {
+                Err(EngineError::Parse(AmountError::Negative(amount.to_string()).to_string()))
+            },
+            (_, Some(to)) if raw_tx.transaction_type != TransactionType::Transfer => {
+                Err(EngineError::Parse(format!("only a transfer has a recipient, this one is to client {}", to)))
+            },
+            _ => Ok(Transaction {
+                transaction_type: raw_tx.transaction_type,
+                client: raw_tx.client,
+                tx: raw_tx.tx,
+                amount: raw_tx.amount,
+                reason: raw_tx.reason,
+                to: raw_tx.to,
+            }),
+        }
+    }
+}
+
 impl From<Transaction> for RawTransaction { // with a From implementation, we automatically get an Into implementation
     fn from(tx: Transaction) -> RawTransaction {
-        match tx.amount {
-            Some(actual_amount) => {
-                RawTransaction {
-                    transaction_type: tx.transaction_type,
-                    client: tx.client,
-                    tx: tx.tx,
-                    amount: Some((actual_amount as f64) / 10000.0 ),
-                }
-            },
-            None => {
-                RawTransaction {
-                    transaction_type: tx.transaction_type,
-                    client: tx.client,
-                    tx: tx.tx,
-                    amount: None,
-                }
-            }
+        RawTransaction {
+            transaction_type: tx.transaction_type,
+            client: tx.client,
+            tx: tx.tx,
+            amount: tx.amount,
+            reason: tx.reason,
+            to: tx.to,
         }
     }
 }
@@ -93,11 +1132,11 @@ impl From<RawClient> for Client {
     fn from(raw_cl: RawClient) -> Client {
         Client {
             client: raw_cl.client,
-            available: (raw_cl.available * 10000.0) as u64, 
-            held: (raw_cl.held * 10000.0) as u64, 
-            total: (raw_cl.total * 10000.0) as u64, 
-            transactions: Vec::new(),
-            locked: raw_cl.locked, 
+            available: raw_cl.available,
+            held: raw_cl.held,
+            total: raw_cl.total,
+            locked: raw_cl.locked,
+            flagged: raw_cl.flagged.unwrap_or(false),
         }
     }
 }
@@ -106,10 +1145,11 @@ impl From<&Client> for RawClient {
     fn from(cl: &Client) -> RawClient {
         RawClient {
             client: cl.client,
-            available: ((cl.available as f64) / 10000.0),
-            held: ((cl.held as f64) / 10000.0),
-            total: ((cl.total as f64) / 10000.0),
+            available: cl.available,
+            held: cl.held,
+            total: cl.total,
             locked: cl.locked,
+            flagged: Some(cl.flagged),
         }
     }
-}
\ No newline at end of file
+}
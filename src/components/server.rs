@@ -0,0 +1,87 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use csv::Writer;
+
+// LOCAL
+use crate::components::data_structures::{PaymentsEngine, AccountOrder, Tally, FraudLog, InputFormat, OutputFormat};
+use crate::components::utilities::{read_shared_transactions, write_accounts};
+
+// the line a connection sends instead of transactions to get the current accounts back
+pub const ACCOUNTS_COMMAND: &str = "ACCOUNTS";
+
+// Server accepts transaction streams over TCP and applies them all to one set of accounts
+// every connection either streams csv in the RawTransaction format (header first) and gets a Tally back once it shuts down its write half,
+// or sends ACCOUNTS on its own line and gets the current accounts back as csv
+pub struct Server {
+    listener: TcpListener,
+    engine: Arc<Mutex<PaymentsEngine>>,
+    order: AccountOrder,
+}
+
+impl Server {
+    // the engine is shared, so the same accounts can also be served over HTTP
+    pub fn bind<A: ToSocketAddrs>(address: A, engine: Arc<Mutex<PaymentsEngine>>, order: AccountOrder) -> Result<Self, Box<dyn Error>> {
+        Ok(Server {
+            listener: TcpListener::bind(address)?,
+            engine,
+            order,
+        })
+    }
+    // the address we actually ended up on, useful when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    // a handle on the shared accounts, e.g. to write them out when the server is stopped
+    pub fn engine(&self) -> Arc<Mutex<PaymentsEngine>> {
+        Arc::clone(&self.engine)
+    }
+    // accept connections until the listener fails, each connection gets its own thread
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        for stream in self.listener.incoming() {
+            // one bad accept (e.g. the peer already hung up) shouldn't take the server down
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("[ERROR]: Could not accept a connection: {}", err);
+                    continue
+                },
+            };
+            let engine = Arc::clone(&self.engine);
+            let order = self.order;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown peer".to_string());
+                if let Err(err) = handle_connection(stream, &peer, &engine, order) {
+                    eprintln!("[ERROR]: Connection from {} failed: {}", peer, err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+// serve a single connection, name is used in fraud warnings to say where a record came from
+pub fn handle_connection(stream: TcpStream, name: &str, engine: &Mutex<PaymentsEngine>, order: AccountOrder) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    if first_line.trim() == ACCOUNTS_COMMAND {
+        // render the accounts before writing them out, so a slow reader doesn't hold the lock
+        let mut accounts = Vec::new();
+        {
+            let engine = engine.lock().map_err(|_| "[ERROR]: Another thread panicked while applying a transaction, the accounts can't be trusted.")?;
+            write_accounts(&engine, order, OutputFormat::Csv, &mut accounts)?;
+        }
+        (&stream).write_all(&accounts)?;
+        return Ok(());
+    }
+    // we've already read the header, so put it back in front of the rest of the stream
+    let mut sinks = (Tally::default(), FraudLog::new(io::stderr()));
+    read_shared_transactions(first_line.as_bytes().chain(reader), InputFormat::Csv, name, engine, &mut sinks)?;
+    let mut writer = Writer::from_writer(&stream);
+    writer.serialize(&sinks.0)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,32 +1,43 @@
 use std::path::Path;
-use std::collections::HashMap;
 use std::ffi::OsString;
-use crate::components::utilities::process_transaction_data;
-use crate::components::data_structures::{RawClient};
+use std::str::FromStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+use crate::components::http::HttpServer;
+use crate::components::server::{Server, ACCOUNTS_COMMAND};
+use crate::components::utilities::{read_fee_schedule, read_trusted_transactions, replay_journal, read_opening_balances, process_parallel, write_snapshot, read_snapshot, process_transaction_data, write_accounts, read_transactions, validate_transactions, diff_accounts};
+use crate::components::cli::{parse_command, Command, Arguments, USAGE, PROCESS_USAGE, DIFF_USAGE};
+use crate::components::data_structures::{Amount, AmountError, RawClient, Transaction, TransactionType, PaymentsEngine, Outcome, EngineError, RejectedRecord, TxIdSet, TxOwners, DisputeState, DisputeEvent, RedisputePolicy, EngineConfig, AccountOrder, RecordSink, RejectionReport, Strict, FraudLog, Trace, TraceRecord, Tally, InputFormat, OutputFormat, Journal, Statement, AuditRecord, AuditReport, WithdrawalDisputePolicy, NegativeAvailablePolicy, StatementRecord, StatementSection, FeeTier, FeeSchedule, FeeBatch};
 
 #[test]
 pub fn positive_dispute() {
     let file_path: OsString = "test_data/positive_dispute.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 0.0,
-        held: 1.0, 
-        total: 1.0,
+        available: Amount::from_str("0.0").unwrap(),
+        held: Amount::from_str("1.0").unwrap(), 
+        total: Amount::from_str("1.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0,
-        held: 1.0, 
-        total: 3.0,
+        available: Amount::from_str("2.0").unwrap(),
+        held: Amount::from_str("1.0").unwrap(), 
+        total: Amount::from_str("3.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 } 
@@ -35,25 +46,27 @@ pub fn positive_dispute() {
 pub fn negative_dispute() {
     let file_path: OsString = "test_data/negative_dispute.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 1.5,
-        held: 0.0, 
-        total: 1.5,
+        available: Amount::from_str("1.5").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("1.5").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0,
-        held: 0.0, 
-        total: 2.0,
+        available: Amount::from_str("2.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("2.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 } 
@@ -62,25 +75,27 @@ pub fn negative_dispute() {
 pub fn postive_resolve() {
     let file_path: OsString = "test_data/positive_resolve.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 5.0,
-        held: 0.0, 
-        total: 5.0,
+        available: Amount::from_str("5.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("5.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0,
-        held: 0.0, 
-        total: 2.0,
+        available: Amount::from_str("2.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("2.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 } 
@@ -89,25 +104,27 @@ pub fn postive_resolve() {
 pub fn negative_resolve() {
     let file_path: OsString = "test_data/negative_resolve.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 1.5,
-        held: 3.5, 
-        total: 5.0,
+        available: Amount::from_str("1.5").unwrap(),
+        held: Amount::from_str("3.5").unwrap(), 
+        total: Amount::from_str("5.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0,
-        held: 0.0, 
-        total: 2.0,
+        available: Amount::from_str("2.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("2.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 } 
@@ -117,17 +134,18 @@ pub fn negative_resolve() {
 pub fn positive_chargeback() {
     let file_path: OsString = "test_data/positive_chargeback.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 80.0,
-        held: 0.0, 
-        total: 80.0,
+        available: Amount::from_str("80.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("80.0").unwrap(),
         locked: true,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
     assert_eq!(test_client1, client1);
 } 
 
@@ -135,25 +153,27 @@ pub fn positive_chargeback() {
 pub fn negative_chargeback() {
     let file_path: OsString = "test_data/negative_chargeback.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 1.5,
-        held: 0.0, 
-        total: 1.5,
+        available: Amount::from_str("1.5").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("1.5").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0,
-        held: 0.0, 
-        total: 2.0,
+        available: Amount::from_str("2.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("2.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 }
@@ -162,25 +182,27 @@ pub fn negative_chargeback() {
 pub fn withdrawals_and_deposits() {
     let file_path: OsString = "test_data/withdrawals_and_deposits.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 8.0,
-        held: 0.0, 
-        total: 8.0,
+        available: Amount::from_str("8.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("8.0").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 0.0001,
-        held: 0.0, 
-        total: 0.0001,
+        available: Amount::from_str("0.0001").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("0.0001").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
 } 
@@ -189,25 +211,1375 @@ pub fn withdrawals_and_deposits() {
 pub fn mixed_test() {
     let file_path: OsString = "test_data/mixed_test.csv".to_string().into();
     let file = Path::new(&file_path);
-    assert_eq!(file.is_file(), true);
-    let mut client_data = HashMap::new();
-    process_transaction_data(&file_path, &mut client_data).unwrap();
+    assert!(file.is_file());
+    let mut engine = PaymentsEngine::new();
+    process_transaction_data(&file_path, &mut engine, &mut ()).unwrap();
     let test_client1 = RawClient {
         client: 1,
-        available: 3453.0,
-        held: 0.0, 
-        total: 3453.0,
+        available: Amount::from_str("3453.0").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("3453.0").unwrap(),
         locked: true,
+        flagged: Some(false),
     };
     let test_client2 = RawClient {
         client: 2,
-        available: 2.0431,
-        held: 0.0, 
-        total: 2.0431,
+        available: Amount::from_str("2.0431").unwrap(),
+        held: Amount::from_str("0.0").unwrap(), 
+        total: Amount::from_str("2.0431").unwrap(),
         locked: false,
+        flagged: Some(false),
     };
-    let client1: RawClient = client_data.get(&1).unwrap().into();
-    let client2: RawClient = client_data.get(&2).unwrap().into();
+    let client1: RawClient = engine.account(1).unwrap().into();
+    let client2: RawClient = engine.account(2).unwrap().into();
     assert_eq!(test_client1, client1);
     assert_eq!(test_client2, client2);
-} 
\ No newline at end of file
+}
+
+#[test]
+pub fn amount_parsing_is_exact() {
+    // values that used to be truncated by the (amount * 10000.0) as u64 conversion
+    assert_eq!(Amount::from_str("0.0003").unwrap(), Amount::from_units(3));
+    assert_eq!(Amount::from_str("2.675").unwrap(), Amount::from_units(26750));
+    assert_eq!(Amount::from_str("1.50000").unwrap(), Amount::from_units(15000));
+    assert_eq!(Amount::from_str("922337203685477.5807").unwrap(), Amount::from_units(i64::MAX));
+    assert_eq!(Amount::from_units(26750).to_string(), "2.675");
+    assert_eq!(Amount::from_units(10000).to_string(), "1.0");
+    assert_eq!(Amount::from_units(1).to_string(), "0.0001");
+}
+
+#[test]
+pub fn amount_parsing_rejects_bad_values() {
+    assert!(matches!(Amount::from_str("0.00001"), Err(AmountError::TooPrecise(_))));
+    assert!(matches!(Amount::from_str("-1.0"), Err(AmountError::Negative(_))));
+    assert!(matches!(Amount::from_str("NaN"), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str("inf"), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str(""), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str("922337203685477.5808"), Err(AmountError::Overflow(_))));
+    // rust_decimal would read these as 1000 and 10
+    assert!(matches!(Amount::from_str("1_000"), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str("1__0"), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str(".5"), Err(AmountError::Invalid(_))));
+    assert!(matches!(Amount::from_str("+1.0"), Err(AmountError::Invalid(_))));
+    // and would round this one to 1.0
+    assert!(matches!(Amount::from_str("1.00000000000000000000000000001"), Err(AmountError::TooPrecise(_))));
+    assert!(matches!(Amount::parse_signed("-1.00000000000000000000000000001"), Err(AmountError::TooPrecise(_))));
+    assert!(matches!(Amount::from_str("99999999999999999999999999999999"), Err(AmountError::Overflow(_))));
+}
+
+// shorthand for building a Transaction in the engine level tests below
+fn transaction(transaction_type: TransactionType, client: u16, tx: u32, amount: Option<&str>) -> Transaction {
+    Transaction {
+        transaction_type,
+        client,
+        tx,
+        amount: amount.map(|text| Amount::from_str(text).unwrap()),
+        reason: None,
+        to: None,
+    }
+}
+
+#[test]
+pub fn engine_apply_reports_outcomes() {
+    let mut engine = PaymentsEngine::new();
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 7, 1, Some("10.5"))).unwrap(), Outcome::Deposited(Amount::from_str("10.5").unwrap()));
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 7, 1, None)).unwrap(), Outcome::Disputed(Amount::from_str("10.5").unwrap()));
+    // everything is held, so there is nothing available to withdraw
+    assert!(engine.apply(transaction(TransactionType::Withdrawal, 7, 2, Some("1.0"))).is_err());
+    let client = engine.account(7).unwrap();
+    assert_eq!(client.available, Amount::ZERO);
+    assert_eq!(client.held, Amount::from_str("10.5").unwrap());
+    assert_eq!(engine.accounts().count(), 1);
+    assert!(engine.account(8).is_none());
+}
+
+#[test]
+pub fn engine_errors_are_typed() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("2.0"))).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("3.0"))).unwrap_err(),
+        EngineError::InsufficientFunds { client: 1, tx: 2, available: Amount::from_str("2.0").unwrap(), requested: Amount::from_str("3.0").unwrap() });
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 1, 3, None)).unwrap_err(), EngineError::MissingAmount { client: 1, tx: 3 });
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 99, None)).unwrap_err(), EngineError::UnknownTransaction { client: 1, tx: 99 });
+    assert_eq!(engine.apply(transaction(TransactionType::Resolve, 1, 1, None)).unwrap_err(), EngineError::NotDisputed { client: 1, tx: 1 });
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap_err(), EngineError::AlreadyDisputed { client: 1, tx: 1 });
+    engine.apply(transaction(TransactionType::Chargeback, 1, 1, None)).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 1, 4, Some("1.0"))).unwrap_err(), EngineError::AccountLocked { client: 1, tx: 4 });
+    engine.apply(transaction(TransactionType::Deposit, 2, 5, Some("922337203685477.5807"))).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 2, 6, Some("0.0001"))).unwrap_err(),
+        EngineError::Overflow { client: 2, tx: 6, amount: Amount::from_units(1) });
+}
+
+#[test]
+pub fn rejection_report() {
+    let file_path: OsString = "test_data/rejections.csv".to_string().into();
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new(Vec::new());
+    process_transaction_data(&file_path, &mut engine, &mut report).unwrap();
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(report.as_bytes());
+    let rejected: Vec<RejectedRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+    let summary: Vec<(u64, &str, &str)> = rejected.iter().map(|r| (r.record, r.row.as_str(), r.reason.as_str())).collect();
+    assert_eq!(summary, vec![
+        (3, "withdrawal,1,3,5.0", "insufficient_funds"),
+        (4, "deposit,2,4,0.00001", "parse_error"),
+        (5, "dispute,1,9,", "unknown_transaction"),
+        (6, "resolve,1,1,", "not_disputed"),
+        (7, "bogus,1,10,1.0", "parse_error"),
+    ]);
+    // the good rows around the bad ones still get applied
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("3.0").unwrap());
+}
+
+#[test]
+pub fn short_and_long_rows_are_rejected_not_fatal() {
+    let mut records = b"type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1\nresolve,1,1,,\ndeposit,1,2,1.0,junk\ndeposit,1,3\ndeposit,1,4,".to_vec();
+    records.extend_from_slice(b"\xff\nwithdrawal,1,5,1.0\n");
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(records.as_slice(), InputFormat::Csv, "records", &mut engine, &mut report).unwrap();
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(report.as_bytes());
+    let rejected: Vec<RejectedRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+    let summary: Vec<(u64, &str, &str)> = rejected.iter().map(|r| (r.record, r.row.as_str(), r.reason.as_str())).collect();
+    // the short dispute and the resolve with a trailing empty field are read as usual
+    assert_eq!(summary, vec![
+        (4, "deposit,1,2,1.0,junk", "parse_error"),
+        (5, "deposit,1,3", "missing_amount"),
+        (6, "deposit,1,4,\u{FFFD}", "parse_error"),
+    ]);
+    let client = engine.account(1).unwrap();
+    assert_eq!((client.available, client.held), (Amount::from_str("4.0").unwrap(), Amount::ZERO));
+}
+
+#[test]
+pub fn rejections_argument() {
+    let args: Vec<OsString> = vec!["test_data/mixed_test.csv".into(), "--rejections".into(), "rejected.csv".into()];
+    let arguments = process_arguments(args);
+    assert_eq!(arguments.inputs, vec![OsString::from("test_data/mixed_test.csv")]);
+    assert_eq!(arguments.rejections, Some(OsString::from("rejected.csv")));
+    assert!(parse_command(vec!["test_data/mixed_test.csv".into(), "--rejections".into()]).is_err());
+    // no input file is an error rather than a panic
+    assert!(parse_command(vec!["process".into()]).is_err());
+}
+
+#[test]
+pub fn transaction_index() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("2.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+    // only deposits and withdrawals are kept, disputes just update the record they reference
+    let record = engine.transaction(2).unwrap();
+    assert_eq!(record.transaction_type, TransactionType::Withdrawal);
+    assert_eq!(record.client, 1);
+    assert_eq!(record.amount, Amount::from_str("2.0").unwrap());
+    assert_eq!(record.state, DisputeState::Disputed);
+    assert_eq!(engine.transaction(1).unwrap().state, DisputeState::Processed);
+    assert!(engine.transaction(3).is_none());
+    // a rejected withdrawal is never recorded, so it can't be disputed
+    assert!(engine.apply(transaction(TransactionType::Withdrawal, 1, 4, Some("100.0"))).is_err());
+    assert!(engine.transaction(4).is_none());
+}
+
+#[test]
+pub fn tx_id_set() {
+    let mut seen = TxIdSet::new();
+    assert!(seen.is_empty());
+    for tx in [0, 63, 64, 65535, 65536, u32::MAX] {
+        assert!(!seen.contains(tx));
+        assert!(seen.insert(tx));
+        assert!(seen.contains(tx));
+    }
+    assert!(!seen.insert(u32::MAX));
+    assert!(!seen.contains(1));
+    assert!(!seen.contains(u32::MAX - 1));
+    assert_eq!(seen.len(), 6);
+}
+
+#[test]
+pub fn duplicate_transactions_are_rejected() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap();
+    // a replayed deposit, the same id on a withdrawal, and the same id from another client are all refused
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 1 });
+    assert_eq!(engine.apply(transaction(TransactionType::Withdrawal, 1, 1, Some("1.0"))).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 1 });
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 2, 1, Some("5.0"))).unwrap_err(), EngineError::DuplicateTransaction { client: 2, tx: 1 });
+    assert_eq!(engine.account(1).unwrap().total, Amount::from_str("5.0").unwrap());
+    // disputes name an existing tx id, so they are never duplicates
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+    // an id is only used up once the transaction is accepted
+    assert!(engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("100.0"))).is_err());
+    engine.apply(transaction(TransactionType::Deposit, 1, 2, Some("1.0"))).unwrap();
+}
+
+#[test]
+pub fn cross_client_references() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap();
+    for transaction_type in [TransactionType::Dispute, TransactionType::Resolve, TransactionType::Chargeback] {
+        assert_eq!(engine.apply(transaction(transaction_type, 2, 1, None)).unwrap_err(), EngineError::ClientMismatch { client: 2, tx: 1, owner: 1 });
+    }
+    // an id nobody owns is still just unknown
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 2, 9, None)).unwrap_err(), EngineError::UnknownTransaction { client: 2, tx: 9 });
+    // client 1's deposit is untouched and can still be disputed by its owner
+    assert_eq!(engine.transaction(1).unwrap().state, DisputeState::Processed);
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+}
+
+#[test]
+pub fn dispute_state_transitions() {
+    use DisputeState::*;
+    use DisputeEvent::*;
+    let not_disputed = Err(EngineError::NotDisputed { client: 1, tx: 2 });
+    let already_disputed = Err(EngineError::AlreadyDisputed { client: 1, tx: 2 });
+    let already_settled = Err(EngineError::AlreadySettled { client: 1, tx: 2 });
+    // (from, event, with RedisputePolicy::Forbid, with RedisputePolicy::Allow)
+    let table = [
+        (Processed, Dispute, Ok(Disputed), Ok(Disputed)),
+        (Processed, Resolve, not_disputed.clone(), not_disputed.clone()),
+        (Processed, Chargeback, not_disputed.clone(), not_disputed),
+        (Disputed, Dispute, already_disputed.clone(), already_disputed),
+        (Disputed, Resolve, Ok(Resolved), Ok(Resolved)),
+        (Disputed, Chargeback, Ok(ChargedBack), Ok(ChargedBack)),
+        (Resolved, Dispute, already_settled.clone(), Ok(Disputed)),
+        (Resolved, Resolve, already_settled.clone(), already_settled.clone()),
+        (Resolved, Chargeback, already_settled.clone(), already_settled.clone()),
+        (ChargedBack, Dispute, already_settled.clone(), already_settled.clone()),
+        (ChargedBack, Resolve, already_settled.clone(), already_settled.clone()),
+        (ChargedBack, Chargeback, already_settled.clone(), already_settled),
+    ];
+    for (from, event, forbid, allow) in table {
+        assert_eq!(from.transition(event, RedisputePolicy::Forbid, 1, 2), forbid, "{:?} + {:?} (forbid)", from, event);
+        assert_eq!(from.transition(event, RedisputePolicy::Allow, 1, 2), allow, "{:?} + {:?} (allow)", from, event);
+    }
+}
+
+#[test]
+pub fn resolved_transactions_stay_resolved() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+    engine.apply(transaction(TransactionType::Resolve, 1, 1, None)).unwrap();
+    assert_eq!(engine.transaction(1).unwrap().state, DisputeState::Resolved);
+    // resolving twice used to hand the client the amount a second time
+    assert_eq!(engine.apply(transaction(TransactionType::Resolve, 1, 1, None)).unwrap_err(), EngineError::AlreadySettled { client: 1, tx: 1 });
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap_err(), EngineError::AlreadySettled { client: 1, tx: 1 });
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("5.0").unwrap());
+}
+
+#[test]
+pub fn redispute_policy() {
+    let mut engine = PaymentsEngine::with_config(EngineConfig { redispute: RedisputePolicy::Allow, ..EngineConfig::default() });
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("5.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+    engine.apply(transaction(TransactionType::Resolve, 1, 1, None)).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 1, None)).unwrap();
+    engine.apply(transaction(TransactionType::Chargeback, 1, 1, None)).unwrap();
+    let client = engine.account(1).unwrap();
+    assert_eq!(client.total, Amount::ZERO);
+    assert!(client.locked);
+    assert_eq!(engine.transaction(1).unwrap().state, DisputeState::ChargedBack);
+}
+
+// every file in test_data has its expected output (sorted by client id) in test_data/expected, named after the input with a .csv extension
+// so inputs holding the same transactions in different formats share one
+#[test]
+pub fn golden_files() {
+    let mut checked = 0;
+    for entry in fs::read_dir("test_data").unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_file() {
+            continue;
+        }
+        let expected_path = Path::new("test_data/expected").join(path.file_name().unwrap()).with_extension("csv");
+        let expected = fs::read_to_string(&expected_path).unwrap_or_else(|_| panic!("missing golden file {:?}", expected_path));
+        let mut engine = PaymentsEngine::new();
+        process_transaction_data(&path.into_os_string(), &mut engine, &mut ()).unwrap();
+        let mut output = Vec::new();
+        write_accounts(&engine, AccountOrder::ClientId, OutputFormat::Csv, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected, "{:?}", expected_path);
+        checked += 1;
+    }
+    assert!(checked >= 9);
+}
+
+#[test]
+pub fn account_ordering() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 3, 1, Some("1.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 1, 2, Some("5.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 2, 3, Some("5.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 4, 4, Some("3.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 4, 4, None)).unwrap();
+    let ids = |order: AccountOrder| -> Vec<u16> { engine.sorted_accounts(order).iter().map(|client| client.client).collect() };
+    assert_eq!(ids(AccountOrder::ClientId), vec![1, 2, 3, 4]);
+    assert_eq!(ids(AccountOrder::Total), vec![1, 2, 4, 3]);
+    assert_eq!(ids(AccountOrder::Available), vec![1, 2, 3, 4]);
+    assert_eq!(ids(AccountOrder::FirstSeen), vec![3, 1, 2, 4]);
+    assert_eq!("first-seen".parse::<AccountOrder>(), Ok(AccountOrder::FirstSeen));
+    assert!("newest".parse::<AccountOrder>().is_err());
+}
+
+#[test]
+pub fn multiple_inputs() {
+    let args: Vec<OsString> = vec!["test_data/mixed_test.csv".into(), "-".into(), "test_data/positive_dispute.csv".into(), "--order".into(), "total".into()];
+    let arguments = process_arguments(args);
+    assert_eq!(arguments.inputs, vec![OsString::from("test_data/mixed_test.csv"), OsString::from("-"), OsString::from("test_data/positive_dispute.csv")]);
+    assert_eq!(arguments.order, AccountOrder::Total);
+    assert!(parse_command(vec!["test_data/mixed_test.csv".into(), "test_data/missing.csv".into()]).is_err());
+}
+
+#[test]
+pub fn read_from_buffers() {
+    // two "files" processed into the same accounts, the second disputes a deposit from the first
+    let monday = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 2, 2, 4.0\n";
+    let tuesday = "type, client, tx, amount\ndispute, 1, 1,\nwithdrawal, 2, 3, 5.0\n";
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(monday.as_bytes(), InputFormat::Csv, "monday", &mut engine, &mut report).unwrap();
+    read_transactions(tuesday.as_bytes(), InputFormat::Csv, "tuesday", &mut engine, &mut report).unwrap();
+    let client1 = engine.account(1).unwrap();
+    assert_eq!(client1.available, Amount::ZERO);
+    assert_eq!(client1.held, Amount::from_str("10.0").unwrap());
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(report.as_bytes());
+    let rejected: Vec<RejectedRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!((rejected[0].input.as_str(), rejected[0].record, rejected[0].reason.as_str()), ("tuesday", 2, "insufficient_funds"));
+}
+
+// helper to parse a process command line, failing the test if it's anything else
+fn process_arguments(args: Vec<OsString>) -> Arguments {
+    match parse_command(args).unwrap() {
+        Command::Process(arguments) => arguments,
+        command => panic!("expected a process command, got {:?}", command),
+    }
+}
+
+#[test]
+pub fn command_parsing() {
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<OsString>>();
+    // a bare input is still a process command, so existing scripts keep working
+    assert_eq!(process_arguments(args(&["test_data/mixed_test.csv"])), process_arguments(args(&["process", "test_data/mixed_test.csv"])));
+    assert_eq!(parse_command(Vec::new()).unwrap(), Command::Help(USAGE));
+    assert_eq!(parse_command(args(&["--help"])).unwrap(), Command::Help(USAGE));
+    assert_eq!(parse_command(args(&["process", "--help"])).unwrap(), Command::Help(PROCESS_USAGE));
+    assert_eq!(parse_command(args(&["diff", "-h"])).unwrap(), Command::Help(DIFF_USAGE));
+    let arguments = process_arguments(args(&["process", "--strict", "--output", "accounts.csv", "--output-format", "csv", "test_data/mixed_test.csv"]));
+    assert!(arguments.strict);
+    assert_eq!(arguments.output, Some(OsString::from("accounts.csv")));
+    assert!(parse_command(args(&["process", "--output-format", "xml", "test_data/mixed_test.csv"])).is_err());
+    assert!(parse_command(args(&["process", "--bogus", "test_data/mixed_test.csv"])).is_err());
+    assert!(matches!(parse_command(args(&["replay", "test_data/mixed_test.csv"])).unwrap(), Command::Replay(_)));
+    assert!(matches!(parse_command(args(&["validate", "test_data/mixed_test.csv"])).unwrap(), Command::Validate(_)));
+    assert_eq!(parse_command(args(&["diff", "a.csv", "b.csv"])).unwrap(), Command::Diff { left: "a.csv".into(), right: "b.csv".into() });
+    assert!(parse_command(args(&["diff", "a.csv"])).is_err());
+    assert_eq!(parse_command(args(&["generate", "--rows", "5"])).unwrap(), Command::Generate { output: "test_data.csv".into(), rows: 5 });
+    assert!(parse_command(args(&["generate", "--rows", "five"])).is_err());
+    // a journal on its own is enough input, but it can't be mixed with another starting point
+    assert_eq!(process_arguments(args(&["--journal", "journal.log"])).journal, Some(OsString::from("journal.log")));
+    assert!(parse_command(args(&["process", "--journal", "journal.log", "--restore", "state.jsonl"])).is_err());
+    assert!(parse_command(args(&["process", "--journal", "journal.log", "--threads", "2"])).is_err());
+    assert!(parse_command(args(&["process", "--audit", "audit.csv", "--threads", "2", "test_data/mixed_test.csv"])).unwrap_err().to_string().contains("--audit"));
+    match parse_command(args(&["statement", "test_data/mixed_test.csv", "--client", "7"])).unwrap() {
+        Command::Statement { client, arguments } => assert_eq!((client, arguments.inputs), (7, args(&["test_data/mixed_test.csv"]))),
+        command => panic!("expected a statement command, got {:?}", command),
+    }
+    assert!(parse_command(args(&["statement", "test_data/mixed_test.csv"])).is_err());
+    let arguments = process_arguments(args(&["test_data/mixed_test.csv", "--withdrawal-disputes", "reverse"]));
+    assert_eq!(arguments.config().withdrawal_disputes, WithdrawalDisputePolicy::ReverseImmediately);
+    assert!(parse_command(args(&["test_data/mixed_test.csv", "--withdrawal-disputes", "refund"])).is_err());
+    assert_eq!(process_arguments(args(&["test_data/mixed_test.csv", "--redispute", "allow"])).config().redispute, RedisputePolicy::Allow);
+    assert_eq!(process_arguments(args(&["test_data/mixed_test.csv"])).config().redispute, RedisputePolicy::Forbid);
+    assert!(parse_command(args(&["test_data/mixed_test.csv", "--redispute", "sometimes"])).is_err());
+    // trust goes with the position an input was given at, not its path, so the same file or STDIN can be given both ways
+    let arguments = process_arguments(args(&["test_data/mixed_test.csv", "--trusted", "test_data/mixed_test.csv", "-", "--trusted", "-"]));
+    assert_eq!(arguments.inputs.len(), 4);
+    assert_eq!((0..4).map(|position| arguments.is_trusted(position)).collect::<Vec<bool>>(), vec![false, true, false, true]);
+    assert!(parse_command(args(&["statement", "--client", "70000", "test_data/mixed_test.csv"])).is_err());
+}
+
+#[test]
+pub fn strict_mode() {
+    let file_path: OsString = "test_data/rejections.csv".to_string().into();
+    let mut engine = PaymentsEngine::new();
+    let mut sinks: Vec<Box<dyn RecordSink>> = vec![Box::new(Strict)];
+    let error = process_transaction_data(&file_path, &mut engine, &mut sinks).unwrap_err();
+    assert!(error.to_string().contains("record #3 (withdrawal,1,3,5.0)"), "{}", error);
+    // everything before the refused record was applied, nothing after it
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("3.0").unwrap());
+    assert!(engine.account(2).is_none());
+}
+
+#[test]
+pub fn replay_trace() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,\nwithdrawal, 1, 2, 1.0\n";
+    let mut engine = PaymentsEngine::new();
+    let mut trace = Trace::new(Vec::new());
+    read_transactions(input.as_bytes(), InputFormat::Csv, "input", &mut engine, &mut trace).unwrap();
+    let trace = String::from_utf8(trace.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(trace.as_bytes());
+    let records: Vec<TraceRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+    let summary: Vec<(&str, Option<String>, Option<String>)> = records.iter()
+        .map(|r| (r.outcome.as_str(), r.available.map(|a| a.to_string()), r.held.map(|a| a.to_string())))
+        .collect();
+    assert_eq!(summary, vec![
+        ("deposited", Some("10.0".to_string()), Some("0.0".to_string())),
+        ("disputed", Some("0.0".to_string()), Some("10.0".to_string())),
+        ("insufficient_funds", Some("0.0".to_string()), Some("10.0".to_string())),
+    ]);
+}
+
+#[test]
+pub fn validate_without_applying() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nbogus, 1, 2, 1.0\nwithdrawal, 1, 3, 1.00001\nwithdrawal, 1, 4, 100.0\n";
+    let mut report = RejectionReport::new(Vec::new());
+    // an overdrawn withdrawal reads fine, only the unreadable rows count
+    assert_eq!(validate_transactions(input.as_bytes(), InputFormat::Csv, "input", &mut report).unwrap(), 2);
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(report.as_bytes());
+    let rejected: Vec<RejectedRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+    assert_eq!(rejected.iter().map(|r| r.record).collect::<Vec<u64>>(), vec![2, 3]);
+}
+
+#[test]
+pub fn diffing_accounts() {
+    let left = "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,2.0,0.0,2.0,false\n3,3.0,0.0,3.0,false\n";
+    let right = "client,available,held,total,locked\n1,1.0000,0.0,1.0,false\n2,2.0,0.0,2.0,true\n4,4.0,0.0,4.0,false\n";
+    let differences = diff_accounts(left.as_bytes(), right.as_bytes()).unwrap();
+    // amounts compare by value, so 1.0 and 1.0000 are the same
+    let summary: Vec<(u16, bool, bool)> = differences.iter().map(|d| (d.client, d.left.is_some(), d.right.is_some())).collect();
+    assert_eq!(summary, vec![(2, true, true), (3, true, false), (4, false, true)]);
+    assert!(diff_accounts(left.as_bytes(), left.as_bytes()).unwrap().is_empty());
+    // a file without the flagged column has nobody flagged
+    let flagged = "client,available,held,total,locked,flagged\n1,1.0,0.0,1.0,false,false\n2,2.0,0.0,2.0,false,true\n3,3.0,0.0,3.0,false,false\n";
+    let differences = diff_accounts(left.as_bytes(), flagged.as_bytes()).unwrap();
+    assert_eq!(differences.iter().map(|d| d.client).collect::<Vec<u16>>(), vec![2]);
+    assert_eq!(differences[0].left.as_ref().unwrap().flagged, Some(false));
+}
+
+#[test]
+pub fn tcp_server_many_connections() {
+    let server = Server::bind("127.0.0.1:0", Arc::new(Mutex::new(PaymentsEngine::new())), AccountOrder::ClientId).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    // 200 connections at once, each depositing 1.0 five times for one of 10 clients, plus a replayed tx id that must be rejected
+    let connections: Vec<_> = (0..200u32).map(|connection| {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let client = connection % 10 + 1;
+            let mut csv = String::from("type,client,tx,amount\n");
+            for n in 0..5 {
+                csv.push_str(&format!("deposit,{},{},1.0\n", client, connection * 5 + n));
+            }
+            csv.push_str(&format!("deposit,{},{},1.0\n", client, connection * 5));
+            stream.write_all(csv.as_bytes()).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            let tally: Tally = csv::Reader::from_reader(reply.as_bytes()).deserialize().next().unwrap().unwrap();
+            tally
+        })
+    }).collect();
+    for connection in connections {
+        assert_eq!(connection.join().unwrap(), Tally { accepted: 5, rejected: 1 });
+    }
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(format!("{}\n", ACCOUNTS_COMMAND).as_bytes()).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    let accounts: Vec<RawClient> = csv::Reader::from_reader(reply.as_bytes()).deserialize().map(|account| account.unwrap()).collect();
+    assert_eq!(accounts.len(), 10);
+    for (id, account) in (1u16..).zip(&accounts) {
+        assert_eq!((account.client, account.total), (id, Amount::from_str("100.0").unwrap()));
+    }
+}
+
+// send one HTTP/1.1 request over a plain TcpStream and return the status and JSON body
+fn http_request(address: std::net::SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", method, path, body.len(), body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+pub fn http_api() {
+    let server = HttpServer::bind("127.0.0.1:0", Arc::new(Mutex::new(PaymentsEngine::new()))).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    // a single transaction, with the amount written as a JSON number
+    let (status, body) = http_request(address, "POST", "/transactions", r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.1234}"#);
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"tx": 1, "client": 1, "accepted": true, "outcome": "deposited", "amount": "10.1234"}));
+    // a batch answers for each transaction in order
+    let batch = r#"[
+        {"type": "deposit", "client": 2, "tx": 2, "amount": "5.0"},
+        {"type": "withdrawal", "client": 2, "tx": 3, "amount": "6.0"},
+        {"type": "dispute", "client": 1, "tx": 1},
+        {"type": "deposit", "client": 2, "tx": 4, "amount": 0.00001},
+        {"type": "dispute", "client": 2, "tx": 1}
+    ]"#;
+    let (status, body) = http_request(address, "POST", "/transactions", batch);
+    assert_eq!(status, 200);
+    let reasons: Vec<Value> = body.as_array().unwrap().iter().map(|response| response.get("reason").cloned().unwrap_or(Value::Null)).collect();
+    assert_eq!(reasons, vec![Value::Null, json!("insufficient_funds"), Value::Null, json!("parse_error"), json!("client_mismatch")]);
+    // a single rejected transaction is a 422 with the reason
+    let (status, body) = http_request(address, "POST", "/transactions", r#"{"type": "deposit", "client": 2, "tx": 2, "amount": "1.0"}"#);
+    assert_eq!((status, &body["reason"]), (422, &json!("duplicate_transaction")));
+    let (status, _) = http_request(address, "POST", "/transactions", "not json");
+    assert_eq!(status, 400);
+    let (status, body) = http_request(address, "GET", "/accounts", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([
+        {"client": 1, "available": "0.0", "held": "10.1234", "total": "10.1234", "locked": false},
+        {"client": 2, "available": "5.0", "held": "0.0", "total": "5.0", "locked": false},
+    ]));
+    let (status, body) = http_request(address, "GET", "/accounts/2", "");
+    assert_eq!((status, &body["total"]), (200, &json!("5.0")));
+    let (status, body) = http_request(address, "GET", "/accounts/1/transactions", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!([{"tx": 1, "type": "deposit", "client": 1, "amount": "10.1234", "state": "disputed"}]));
+    assert_eq!(http_request(address, "GET", "/accounts/9", "").0, 404);
+    assert_eq!(http_request(address, "GET", "/accounts/nobody", "").0, 400);
+    assert_eq!(http_request(address, "DELETE", "/accounts", "").0, 405);
+    assert_eq!(http_request(address, "GET", "/nowhere", "").0, 404);
+}
+
+#[test]
+pub fn parallel_matches_single_threaded() {
+    // the same inputs, in the same order, must give the same accounts however many shards they're spread over
+    // shared_ids.csv has clients on different shards reaching for the same ids, including one freed up by a refused withdrawal
+    let mut inputs: Vec<OsString> = fs::read_dir("test_data").unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "csv"))
+        .map(|path| path.into_os_string())
+        .collect();
+    inputs.sort();
+    let mut engine = PaymentsEngine::new();
+    for input in &inputs {
+        process_transaction_data(input, &mut engine, &mut ()).unwrap();
+    }
+    let accounts = |engine: &PaymentsEngine, order| engine.sorted_accounts(order).into_iter().map(RawClient::from).collect::<Vec<RawClient>>();
+    for shards in [1, 2, 3, 8] {
+        let parallel = process_parallel(&inputs, None, PaymentsEngine::new(), shards, &mut FraudLog::new(io::sink())).unwrap();
+        assert_eq!(accounts(&parallel, AccountOrder::ClientId), accounts(&engine, AccountOrder::ClientId), "{} shards", shards);
+        assert_eq!(accounts(&parallel, AccountOrder::FirstSeen), accounts(&engine, AccountOrder::FirstSeen), "{} shards", shards);
+    }
+}
+
+#[test]
+pub fn parallel_catches_ids_shared_across_shards() {
+    let path = std::env::temp_dir().join(format!("shared_ids_{}.csv", std::process::id()));
+    // clients 1 and 2 land on different shards, so only the reader can see them both
+    fs::write(&path, "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,1,7.0\ndispute,2,1,\ndeposit,2,2,3.0\n").unwrap();
+    let mut fraud = FraudLog::new(Vec::new());
+    let engine = process_parallel(&[path.clone().into_os_string()], None, PaymentsEngine::new(), 2, &mut fraud).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("5.0").unwrap());
+    assert_eq!(engine.account(1).unwrap().held, Amount::ZERO);
+    assert_eq!(engine.account(2).unwrap().total, Amount::from_str("3.0").unwrap());
+    assert_eq!(engine.transaction(1).unwrap().client, 1);
+    // the dispute is the only record reaching for another client's tx
+    let warnings = String::from_utf8(fraud.into_inner()).unwrap();
+    assert_eq!(warnings.lines().count(), 1);
+    assert!(warnings.contains("record #3: client 2 referenced tx 1, which belongs to client 1"));
+}
+
+#[test]
+pub fn fraud_log() {
+    let mut engine = PaymentsEngine::new();
+    let mut fraud = FraudLog::new(Vec::new());
+    let input = "type,client,tx,amount\ndeposit,1,1,5.0\nresolve,2,1,\ndispute,1,1,\ndispute,1,9,\n";
+    read_transactions(input.as_bytes(), InputFormat::Csv, "partner.csv", &mut engine, &mut fraud).unwrap();
+    // an unknown tx id is just a mistake, only the resolve naming client 1's deposit is a warning
+    assert_eq!(String::from_utf8(fraud.into_inner()).unwrap(), "[WARNING]: Possible fraud at partner.csv record #2: client 2 referenced tx 1, which belongs to client 1.\n");
+    let mut fraud = FraudLog::new(Vec::new());
+    fraud.check("POST /transactions", 4, None, &Err(EngineError::ClientMismatch { client: 3, tx: 1, owner: 1 }), None).unwrap();
+    fraud.check("POST /transactions", 5, None, &Err(EngineError::UnknownTransaction { client: 3, tx: 8 }), None).unwrap();
+    assert_eq!(String::from_utf8(fraud.into_inner()).unwrap().lines().count(), 1);
+}
+
+#[test]
+pub fn tx_owners_pending_claims() {
+    let mut owners = TxOwners::new();
+    owners.propose(7, 1);
+    assert_eq!((owners.owner(7), owners.claimant(7)), (None, Some(1)));
+    // refused, so the id is free for anyone again
+    owners.settle(7, false);
+    assert_eq!((owners.owner(7), owners.claimant(7)), (None, None));
+    owners.propose(7, 2);
+    owners.propose(7, 2);
+    // the first of client 2's claims is refused, the second is still out and then accepted
+    owners.settle(7, false);
+    assert_eq!(owners.claimant(7), Some(2));
+    owners.settle(7, true);
+    assert_eq!((owners.owner(7), owners.claimant(7)), (Some(2), None));
+    // later decisions on an id that's already owned change nothing
+    owners.settle(7, false);
+    assert_eq!(owners.owner(7), Some(2));
+    assert_eq!(owners.claim(7, 3), 2);
+    let mut set = TxIdSet::new();
+    set.insert(70_000);
+    assert!(set.remove(70_000));
+    assert!(!set.remove(70_000) && !set.remove(u32::MAX));
+    assert!(set.is_empty());
+}
+
+#[test]
+pub fn tx_id_set_union() {
+    let mut left = TxIdSet::new();
+    let mut right = TxIdSet::new();
+    for tx in [1, 70_000, u32::MAX] {
+        left.insert(tx);
+    }
+    for tx in [1, 2, 200_000] {
+        right.insert(tx);
+    }
+    left.union(right);
+    assert_eq!(left.len(), 5);
+    assert!([1, 2, 70_000, 200_000, u32::MAX].iter().all(|tx| left.contains(*tx)));
+}
+
+#[test]
+pub fn json_lines_input() {
+    // the same transactions as rejections.csv, with some amounts as numbers, stray whitespace and a blank line
+    let csv_path: OsString = "test_data/rejections.csv".into();
+    let jsonl_path: OsString = "test_data/rejections.jsonl".into();
+    assert_eq!(InputFormat::from_path(&jsonl_path), InputFormat::Jsonl);
+    assert_eq!(InputFormat::from_path(&csv_path), InputFormat::Csv);
+    let mut reasons = Vec::new();
+    for path in [&csv_path, &jsonl_path] {
+        let mut engine = PaymentsEngine::new();
+        let mut report = RejectionReport::new(Vec::new());
+        process_transaction_data(path, &mut engine, &mut report).unwrap();
+        let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+        let mut reader = csv::Reader::from_reader(report.as_bytes());
+        let rejected: Vec<RejectedRecord> = reader.deserialize().map(|record| record.unwrap()).collect();
+        reasons.push(rejected.iter().map(|r| (r.record, r.reason.clone())).collect::<Vec<(u64, String)>>());
+        assert_eq!(engine.account(1).unwrap().available, Amount::from_str("3.0").unwrap());
+        assert!(engine.account(2).is_none());
+    }
+    assert_eq!(reasons[0], reasons[1]);
+    // a rejected line is reported as it was written
+    let mut report = RejectionReport::new(Vec::new());
+    validate_transactions(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": -1}"#.as_bytes(), InputFormat::Jsonl, "input", &mut report).unwrap();
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let rejected: RejectedRecord = csv::Reader::from_reader(report.as_bytes()).deserialize().next().unwrap().unwrap();
+    assert_eq!(rejected.row, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": -1}"#);
+}
+
+#[test]
+pub fn account_output_formats() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 2, 1, Some("1.5"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 1, 2, Some("2"))).unwrap();
+    let written = |format| {
+        let mut output = Vec::new();
+        write_accounts(&engine, AccountOrder::ClientId, format, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(written(OutputFormat::Csv), "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,1.5,0.0,1.5,false\n");
+    assert_eq!(written(OutputFormat::Jsonl), concat!(
+        r#"{"client":1,"available":"2.0","held":"0.0","total":"2.0","locked":false}"#, "\n",
+        r#"{"client":2,"available":"1.5","held":"0.0","total":"1.5","locked":false}"#, "\n",
+    ));
+    // nobody can be flagged under the default NegativeAvailablePolicy, so there's only a flagged column where they can
+    let mut allowed = PaymentsEngine::with_config(EngineConfig { negative_available: NegativeAvailablePolicy::AllowUnderDispute, ..EngineConfig::default() });
+    allowed.apply(transaction(TransactionType::Deposit, 1, 1, Some("2"))).unwrap();
+    let mut output = Vec::new();
+    write_accounts(&allowed, AccountOrder::ClientId, OutputFormat::Csv, &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked,flagged\n1,2.0,0.0,2.0,false,false\n");
+    let json: Vec<RawClient> = serde_json::from_str(&written(OutputFormat::Json)).unwrap();
+    assert_eq!(json.iter().map(|account| account.client).collect::<Vec<u16>>(), vec![1, 2]);
+    assert_eq!(OutputFormat::from_path(&OsString::from("accounts.json")), OutputFormat::Json);
+    assert_eq!(OutputFormat::from_path(&OsString::from("accounts.JSONL")), OutputFormat::Jsonl);
+    assert_eq!(OutputFormat::from_path(&OsString::from("accounts.txt")), OutputFormat::Csv);
+}
+
+#[test]
+pub fn snapshot_round_trip() {
+    // yesterday: two clients, one deposit still under dispute and one charged back
+    let yesterday = "type,client,tx,amount\ndeposit,2,1,10.0\ndeposit,1,2,5.0\ndeposit,1,3,1.0\ndispute,2,1,\ndispute,1,3,\nchargeback,1,3,\n";
+    let mut engine = PaymentsEngine::new();
+    read_transactions(yesterday.as_bytes(), InputFormat::Csv, "yesterday", &mut engine, &mut ()).unwrap();
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let mut restored = read_snapshot(snapshot.as_slice(), EngineConfig::default()).unwrap();
+    let accounts = |engine: &PaymentsEngine| engine.sorted_accounts(AccountOrder::FirstSeen).into_iter().map(RawClient::from).collect::<Vec<RawClient>>();
+    assert_eq!(accounts(&restored), accounts(&engine));
+    // writing the restored engine again gives the same snapshot
+    let mut again = Vec::new();
+    write_snapshot(&restored, &mut again).unwrap();
+    assert_eq!(again, snapshot);
+    // today: the dispute from yesterday can be resolved, and yesterday's tx ids can't be reused
+    let today = "type,client,tx,amount\nresolve,2,1,\ndeposit,2,2,1.0\ndeposit,1,4,1.0\n";
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(today.as_bytes(), InputFormat::Csv, "today", &mut restored, &mut report).unwrap();
+    assert_eq!(restored.account(2).unwrap().available, Amount::from_str("10.0").unwrap());
+    assert_eq!(restored.transaction(1).unwrap().state, DisputeState::Resolved);
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let rejected: Vec<RejectedRecord> = csv::Reader::from_reader(report.as_bytes()).deserialize().map(|record| record.unwrap()).collect();
+    let reasons: Vec<&str> = rejected.iter().map(|r| r.reason.as_str()).collect();
+    // client 1 was locked by yesterday's chargeback
+    assert_eq!(reasons, vec!["duplicate_transaction", "account_locked"]);
+}
+
+#[test]
+pub fn snapshot_errors() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("1.0"))).unwrap();
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let snapshot = String::from_utf8(snapshot).unwrap();
+    let restore = |text: &str| read_snapshot(text.as_bytes(), EngineConfig::default()).map(|_| ()).unwrap_err().to_string();
+    assert!(restore("").contains("empty"));
+    assert!(restore(&snapshot.replace("\"version\":1", "\"version\":2")).contains("version 2 is not supported"));
+    // cut off before the transaction line
+    let truncated: String = snapshot.lines().take(2).map(|line| format!("{}\n", line)).collect();
+    assert!(restore(&truncated).contains("ends at line 3"));
+    assert!(restore(&format!("{}{}", snapshot, snapshot.lines().last().unwrap())).contains("carries on past line 3"));
+    assert!(restore(&snapshot.replace("\"client\":1,\"amount\"", "\"client\":7,\"amount\"")).contains("no account"));
+}
+
+#[test]
+pub fn parallel_from_a_snapshot() {
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("4.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 2, 2, Some("3.0"))).unwrap();
+    let path = std::env::temp_dir().join(format!("after_snapshot_{}.csv", std::process::id()));
+    // a dispute of a restored transaction, a reuse of a restored id by another client, and a cross-client dispute
+    fs::write(&path, "type,client,tx,amount\ndispute,1,1,\ndeposit,1,2,1.0\ndispute,2,1,\n").unwrap();
+    let parallel = process_parallel(&[path.clone().into_os_string()], None, engine, 2, &mut FraudLog::new(io::sink())).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(parallel.account(1).unwrap().held, Amount::from_str("4.0").unwrap());
+    assert_eq!(parallel.account(1).unwrap().total, Amount::from_str("4.0").unwrap());
+    assert_eq!(parallel.account(2).unwrap().total, Amount::from_str("3.0").unwrap());
+}
+
+#[test]
+pub fn opening_balances() {
+    let balances = "client,available,held,total,locked\n3,10.0,0.0,10.0,false\n1,1.5,0.5,2.0,false\n2,4.0,0.0,4.0,true\n";
+    let mut engine = read_opening_balances(balances.as_bytes(), EngineConfig::default()).unwrap();
+    // clients keep the order they were given in
+    let order: Vec<u16> = engine.sorted_accounts(AccountOrder::FirstSeen).iter().map(|client| client.client).collect();
+    assert_eq!(order, vec![3, 1, 2]);
+    let today = "type,client,tx,amount\nwithdrawal,3,1,2.5\ndeposit,1,2,1.0\ndeposit,2,3,1.0\n";
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(today.as_bytes(), InputFormat::Csv, "today", &mut engine, &mut report).unwrap();
+    assert_eq!(engine.account(3).unwrap().available, Amount::from_str("7.5").unwrap());
+    assert_eq!(engine.account(1).unwrap().total, Amount::from_str("3.0").unwrap());
+    assert_eq!(engine.account(1).unwrap().held, Amount::from_str("0.5").unwrap());
+    // a locked opening balance stays locked
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let rejected: Vec<RejectedRecord> = csv::Reader::from_reader(report.as_bytes()).deserialize().map(|record| record.unwrap()).collect();
+    assert_eq!(rejected.iter().map(|r| r.reason.as_str()).collect::<Vec<&str>>(), vec!["account_locked"]);
+    // the file is refused as a whole if any row doesn't add up, repeats a client or can't be read
+    let refuse = |text: &str| read_opening_balances(text.as_bytes(), EngineConfig::default()).map(|_| ()).unwrap_err().to_string();
+    assert!(refuse("client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,1.0,1.0,1.0,false\n").contains("#2 for client: 2 doesn't add up"));
+    assert!(refuse("client,available,held,total,locked\n1,1.0,0.0,1.0,false\n1,1.0,0.0,1.0,false\n").contains("repeats client: 1"));
+    assert!(read_opening_balances("client,available,held,total,locked\n1,-1.0,0.0,-1.0,false\n".as_bytes(), EngineConfig::default()).is_err());
+    // the accounts a run writes out can be read back as opening balances
+    let mut output = Vec::new();
+    write_accounts(&engine, AccountOrder::ClientId, OutputFormat::Csv, &mut output).unwrap();
+    let reopened = read_opening_balances(output.as_slice(), EngineConfig::default()).unwrap();
+    assert_eq!(reopened.account(3).unwrap().total, engine.account(3).unwrap().total);
+}
+
+#[test]
+pub fn journal_replay() {
+    let mut inputs: Vec<_> = fs::read_dir("test_data").unwrap().map(|entry| entry.unwrap().path()).filter(|path| path.is_file()).collect();
+    inputs.sort();
+    // two runs appending to the same journal, every test file applied to one set of accounts
+    let (monday, tuesday) = inputs.split_at(inputs.len() / 2);
+    let mut engine = PaymentsEngine::new();
+    let mut journal = Journal::new(Vec::new(), 1);
+    for input in monday {
+        process_transaction_data(&input.clone().into_os_string(), &mut engine, &mut journal).unwrap();
+    }
+    let mut written = journal.into_inner().unwrap();
+    let mut rebuilt = PaymentsEngine::new();
+    let sequence = replay_journal(written.as_slice(), &mut rebuilt, &mut ()).unwrap();
+    let mut journal = Journal::new(Vec::new(), sequence);
+    for input in tuesday {
+        process_transaction_data(&input.clone().into_os_string(), &mut rebuilt, &mut journal).unwrap();
+    }
+    written.extend(journal.into_inner().unwrap());
+    for input in tuesday {
+        process_transaction_data(&input.clone().into_os_string(), &mut engine, &mut ()).unwrap();
+    }
+    // rebuilding from the journal alone gives byte for byte the same accounts
+    let mut replayed = PaymentsEngine::new();
+    let mut tally = Tally::default();
+    replay_journal(written.as_slice(), &mut replayed, &mut tally).unwrap();
+    assert!(tally.rejected > 0);
+    for order in [AccountOrder::ClientId, AccountOrder::FirstSeen] {
+        let mut expected = Vec::new();
+        write_accounts(&engine, order, OutputFormat::Csv, &mut expected).unwrap();
+        let mut output = Vec::new();
+        write_accounts(&replayed, order, OutputFormat::Csv, &mut output).unwrap();
+        assert_eq!(output, expected);
+    }
+    // and the same disputable transactions
+    let (mut expected, mut output) = (Vec::new(), Vec::new());
+    write_snapshot(&engine, &mut expected).unwrap();
+    write_snapshot(&replayed, &mut output).unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+pub fn journal_errors() {
+    let mut journal = Journal::new(Vec::new(), 1);
+    let input = "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,2,2,1.0\nbogus,1,3,\ndispute,1,1,\n";
+    read_transactions(input.as_bytes(), InputFormat::Csv, "input", &mut PaymentsEngine::new(), &mut journal).unwrap();
+    let written = String::from_utf8(journal.into_inner().unwrap()).unwrap();
+    assert_eq!(written.lines().count(), 4);
+    let replay = |text: &str, engine: &mut PaymentsEngine| replay_journal(text.as_bytes(), engine, &mut ()).map(|_| ()).unwrap_err().to_string();
+    // an edited amount no longer matches its checksum
+    assert!(replay(&written.replacen("2.0", "9.0", 1), &mut PaymentsEngine::new()).contains("line 1 is corrupt"));
+    // a lost line shows up as a gap in the sequence
+    let missing: String = written.lines().enumerate().filter(|(line, _)| *line != 1).map(|(_, line)| format!("{}\n", line)).collect();
+    assert!(replay(&missing, &mut PaymentsEngine::new()).contains("line 2 is entry 3, expected entry 2"));
+    // replaying onto accounts the journal didn't come from comes out differently
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("1.0"))).unwrap();
+    assert!(replay(&written, &mut engine).contains("entry 1 was deposited when it was journaled but duplicate_transaction"));
+    // a record that couldn't be read is kept, and the refused withdrawal still opens client 2's account
+    let mut engine = PaymentsEngine::new();
+    assert_eq!(replay_journal(written.as_bytes(), &mut engine, &mut ()).unwrap(), 5);
+    assert!(engine.account(2).is_some());
+    assert_eq!(engine.account(1).unwrap().held, Amount::from_str("2.0").unwrap());
+}
+
+#[test]
+pub fn client_statement() {
+    let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\nwithdrawal,1,3,9.0\ndispute,2,1,\ndeposit,1,4,1.0\ndispute,1,1,\nchargeback,1,1,\ndeposit,1,5,1.0\n";
+    let mut statement = Statement::new(Vec::new(), 1);
+    read_transactions(input.as_bytes(), InputFormat::Csv, "input", &mut PaymentsEngine::new(), &mut statement).unwrap();
+    let statement = String::from_utf8(statement.into_inner().unwrap()).unwrap();
+    let rows: Vec<TraceRecord> = csv::Reader::from_reader(statement.as_bytes()).deserialize().map(|row| row.unwrap()).collect();
+    // client 2's deposit is left out, but their dispute of client 1's deposit is in, with client 1's balances
+    let steps: Vec<(u64, &str, Option<Amount>, Option<Amount>)> = rows.iter().map(|row| (row.record, row.outcome.as_str(), row.available, row.held)).collect();
+    let amount = |text: &str| Some(Amount::from_str(text).unwrap());
+    assert_eq!(steps, vec![
+        (1, "deposited", amount("5.0"), amount("0.0")),
+        (3, "insufficient_funds", amount("5.0"), amount("0.0")),
+        (4, "client_mismatch", amount("5.0"), amount("0.0")),
+        (5, "deposited", amount("6.0"), amount("0.0")),
+        (6, "disputed", amount("1.0"), amount("5.0")),
+        (7, "charged_back", amount("1.0"), amount("0.0")),
+        (8, "account_locked", amount("1.0"), amount("0.0")),
+    ]);
+    assert_eq!(rows[4].total, amount("6.0"));
+    assert_eq!(rows[5].total, amount("1.0"));
+    assert_eq!(rows[5].locked, Some(true));
+    // a client we've never heard of gets an empty statement, not even a header
+    let mut statement = Statement::new(Vec::new(), 9);
+    read_transactions(input.as_bytes(), InputFormat::Csv, "input", &mut PaymentsEngine::new(), &mut statement).unwrap();
+    assert!(statement.into_inner().unwrap().is_empty());
+}
+
+#[test]
+pub fn admin_transactions() {
+    let mut engine = PaymentsEngine::new();
+    // an admin transaction from an ordinary source is refused, and doesn't even open an account
+    let unlock = transaction(TransactionType::Unlock, 1, 0, None);
+    assert_eq!(engine.apply(unlock.clone()).unwrap_err(), EngineError::Untrusted { client: 1, tx: 0 });
+    assert!(engine.account(1).is_none());
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 1, 2, Some("4.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+    engine.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap();
+    assert!(engine.account(1).unwrap().locked);
+    // after review the operator takes a fee off the frozen account and unlocks it
+    let mut fee = transaction(TransactionType::Adjustment, 1, 3, None);
+    fee.amount = Some(Amount::parse_signed("-1.5").unwrap());
+    fee.reason = Some("chargeback fee".to_string());
+    assert_eq!(engine.apply_trusted(fee.clone()).unwrap(), Outcome::Adjusted(Amount::parse_signed("-1.5").unwrap()));
+    assert_eq!(engine.apply_trusted(unlock).unwrap(), Outcome::Unlocked);
+    engine.apply(transaction(TransactionType::Withdrawal, 1, 4, Some("1.0"))).unwrap();
+    let account = engine.account(1).unwrap();
+    assert_eq!((account.available, account.total, account.locked), (Amount::from_str("7.5").unwrap(), Amount::from_str("7.5").unwrap(), false));
+    // the same adjustment can't be applied twice, can't be disputed, and can't take more than is available
+    assert_eq!(engine.apply_trusted(fee).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 3 });
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 3, None)).unwrap_err(), EngineError::NotDisputable { client: 1, tx: 3 });
+    let mut too_much = transaction(TransactionType::Adjustment, 1, 5, None);
+    too_much.amount = Some(Amount::parse_signed("-8.0").unwrap());
+    assert!(matches!(engine.apply_trusted(too_much).unwrap_err(), EngineError::InsufficientFunds { .. }));
+    // and locking works without a chargeback
+    assert_eq!(engine.apply_trusted(transaction(TransactionType::Lock, 1, 0, None)).unwrap(), Outcome::Locked);
+    assert_eq!(engine.apply(transaction(TransactionType::Deposit, 1, 6, Some("1.0"))).unwrap_err(), EngineError::AccountLocked { client: 1, tx: 6 });
+}
+
+#[test]
+pub fn admin_audit_report() {
+    let admin = "type,client,tx,amount,reason\ndeposit,1,1,5.0,\nadjustment,1,2,-2.0,correcting a double credit\nadjustment,1,3,0.5,goodwill\nlock,1,4,,under review\n";
+    let mut trusted = AuditReport::new(Vec::new());
+    let mut engine = PaymentsEngine::new();
+    read_trusted_transactions(admin.as_bytes(), InputFormat::Csv, "admin", &mut engine, &mut trusted).unwrap();
+    assert_eq!(engine.account(1).unwrap().total, Amount::from_str("3.5").unwrap());
+    assert!(engine.account(1).unwrap().locked);
+    // the same file from an ordinary source only gets its deposit in (the tx ids are new so nothing is a duplicate)
+    let mut untrusted = AuditReport::new(Vec::new());
+    let mut partner = PaymentsEngine::new();
+    read_transactions(admin.as_bytes(), InputFormat::Csv, "partner", &mut partner, &mut untrusted).unwrap();
+    assert_eq!(partner.account(1).unwrap().total, Amount::from_str("5.0").unwrap());
+    let rows = |report: AuditReport<Vec<u8>>| -> Vec<AuditRecord> {
+        let report = report.into_inner().unwrap();
+        csv::Reader::from_reader(report.as_slice()).deserialize().map(|row| row.unwrap()).collect()
+    };
+    let trusted = rows(trusted);
+    // only admin transactions are audited
+    assert_eq!(trusted.iter().map(|row| row.outcome.as_str()).collect::<Vec<&str>>(), vec!["adjusted", "adjusted", "locked"]);
+    assert_eq!(trusted[0].amount, Some(Amount::parse_signed("-2.0").unwrap()));
+    assert_eq!(trusted[0].reason.as_deref(), Some("correcting a double credit"));
+    assert_eq!(trusted[2].action, TransactionType::Lock);
+    let untrusted = rows(untrusted);
+    assert!(untrusted.iter().all(|row| row.outcome == "untrusted_source" && !row.trusted));
+    // only an adjustment can have a negative amount
+    let mut report = RejectionReport::new(Vec::new());
+    read_trusted_transactions("type,client,tx,amount\ndeposit,1,9,-1.0\n".as_bytes(), InputFormat::Csv, "admin", &mut engine, &mut report).unwrap();
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    assert!(report.contains("parse_error") && report.contains("is negative"), "{}", report);
+    // a journal remembers which records were trusted, so they replay the same way
+    let mut journal = Journal::new(Vec::new(), 1);
+    read_trusted_transactions(admin.as_bytes(), InputFormat::Csv, "admin", &mut PaymentsEngine::new(), &mut journal).unwrap();
+    let mut replayed = PaymentsEngine::new();
+    replay_journal(journal.into_inner().unwrap().as_slice(), &mut replayed, &mut ()).unwrap();
+    assert_eq!(replayed.account(1).unwrap().total, Amount::from_str("3.5").unwrap());
+}
+
+#[test]
+pub fn withdrawal_disputes_settle_under_the_policy_they_were_opened_under() {
+    let hold = EngineConfig { withdrawal_disputes: WithdrawalDisputePolicy::HoldAsRefund, ..EngineConfig::default() };
+    let reverse = EngineConfig { withdrawal_disputes: WithdrawalDisputePolicy::ReverseImmediately, ..EngineConfig::default() };
+    let mut engine = PaymentsEngine::with_config(hold);
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("4.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+    assert_eq!(engine.transaction(2).unwrap().dispute_policy, Some(WithdrawalDisputePolicy::HoldAsRefund));
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    assert!(String::from_utf8(snapshot.clone()).unwrap().contains(r#""dispute_policy":"hold""#));
+    // the next run has switched to reverse, the held refund still comes back on a resolve and goes on a chargeback
+    let balances = |engine: &PaymentsEngine| {
+        let client = engine.account(1).unwrap();
+        (client.available.to_string(), client.held.to_string(), client.total.to_string(), client.locked)
+    };
+    let mut resolved = read_snapshot(snapshot.as_slice(), reverse).unwrap();
+    resolved.apply(transaction(TransactionType::Resolve, 1, 2, None)).unwrap();
+    assert_eq!(balances(&resolved), ("10.0".to_string(), "0.0".to_string(), "10.0".to_string(), false));
+    let mut charged_back = read_snapshot(snapshot.as_slice(), reverse).unwrap();
+    charged_back.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap();
+    assert_eq!(balances(&charged_back), ("6.0".to_string(), "0.0".to_string(), "6.0".to_string(), true));
+    // a snapshot can't say a deposit was disputed under a withdrawal policy, or a withdrawal under reject
+    let text = String::from_utf8(snapshot).unwrap();
+    let restore = |text: &str| read_snapshot(text.as_bytes(), hold).map(|_| ()).unwrap_err().to_string();
+    assert!(restore(&text.replace(r#""dispute_policy":"hold""#, r#""dispute_policy":"reject""#)).contains("reject policy"));
+    let deposit = text.lines().find(|line| line.contains(r#""tx":1,"#)).unwrap();
+    assert!(restore(&text.replace(deposit, &deposit.replace(r#""state""#, r#""dispute_policy":"hold","state""#))).contains("only withdrawals have one"));
+}
+
+#[test]
+pub fn withdrawal_dispute_policies() {
+    // (policy, balances while disputed, after a resolve, after a chargeback) as (available, held, total)
+    let cases = [
+        (WithdrawalDisputePolicy::HoldAsRefund, ("6.0", "4.0", "10.0"), ("10.0", "0.0", "10.0"), ("6.0", "0.0", "6.0")),
+        (WithdrawalDisputePolicy::ReverseImmediately, ("10.0", "0.0", "10.0"), ("10.0", "0.0", "10.0"), ("6.0", "0.0", "6.0")),
+    ];
+    let balances = |engine: &PaymentsEngine| {
+        let client = engine.account(1).unwrap();
+        (client.available, client.held, client.total)
+    };
+    let amounts = |(available, held, total): (&str, &str, &str)| (Amount::from_str(available).unwrap(), Amount::from_str(held).unwrap(), Amount::from_str(total).unwrap());
+    for (policy, disputed, resolved, charged_back) in cases {
+        let disputed_engine = || {
+            let mut engine = PaymentsEngine::with_config(EngineConfig { withdrawal_disputes: policy, ..EngineConfig::default() });
+            engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+            engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("4.0"))).unwrap();
+            assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap(), Outcome::Disputed(Amount::from_str("4.0").unwrap()));
+            assert_eq!(balances(&engine), amounts(disputed), "{:?} disputed", policy);
+            engine
+        };
+        let mut engine = disputed_engine();
+        engine.apply(transaction(TransactionType::Resolve, 1, 2, None)).unwrap();
+        assert_eq!(balances(&engine), amounts(resolved), "{:?} resolved", policy);
+        assert!(!engine.account(1).unwrap().locked);
+        let mut engine = disputed_engine();
+        engine.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap();
+        assert_eq!(balances(&engine), amounts(charged_back), "{:?} charged back", policy);
+        assert!(engine.account(1).unwrap().locked);
+    }
+    // a reversed withdrawal's refund that has since been spent can only be recovered where disputes may take balances negative
+    let spent = |negative_available| {
+        let config = EngineConfig { withdrawal_disputes: WithdrawalDisputePolicy::ReverseImmediately, negative_available, ..EngineConfig::default() };
+        let mut engine = PaymentsEngine::with_config(config);
+        engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+        engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("4.0"))).unwrap();
+        engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+        engine.apply(transaction(TransactionType::Withdrawal, 1, 3, Some("8.0"))).unwrap();
+        (config, engine)
+    };
+    // so under the default policy the chargeback is refused and the dispute stays open
+    let (config, mut engine) = spent(NegativeAvailablePolicy::Forbid);
+    assert!(matches!(engine.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap_err(), EngineError::InsufficientFunds { .. }));
+    assert_eq!(engine.transaction(2).unwrap().state, DisputeState::Disputed);
+    assert_eq!(balances(&engine), amounts(("2.0", "0.0", "2.0")));
+    assert!(!engine.account(1).unwrap().locked && !engine.account(1).unwrap().flagged);
+    let (config_allowed, mut engine_allowed) = spent(NegativeAvailablePolicy::AllowUnderDispute);
+    assert_eq!(engine_allowed.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap(), Outcome::ChargedBack(Amount::from_str("4.0").unwrap()));
+    let account = engine_allowed.account(1).unwrap();
+    assert_eq!((account.available, account.held, account.total), (Amount::parse_signed("-2.0").unwrap(), Amount::ZERO, Amount::parse_signed("-2.0").unwrap()));
+    assert!(account.locked && account.flagged);
+    // either way the accounts written are ones the same policy takes back as opening balances
+    for (config, engine) in [(config, &engine), (config_allowed, &engine_allowed)] {
+        let mut accounts = Vec::new();
+        write_accounts(engine, AccountOrder::ClientId, OutputFormat::Csv, &mut accounts).unwrap();
+        let opened = read_opening_balances(accounts.as_slice(), config).unwrap();
+        assert_eq!(opened.account(1).unwrap().total, engine.account(1).unwrap().total);
+    }
+    // and with Reject nothing moves, the withdrawal was never disputed so it can't be resolved either
+    let mut engine = PaymentsEngine::with_config(EngineConfig { withdrawal_disputes: WithdrawalDisputePolicy::Reject, ..EngineConfig::default() });
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Withdrawal, 1, 2, Some("4.0"))).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap_err(), EngineError::NotDisputable { client: 1, tx: 2 });
+    assert_eq!(engine.apply(transaction(TransactionType::Resolve, 1, 2, None)).unwrap_err(), EngineError::NotDisputed { client: 1, tx: 2 });
+    assert_eq!(balances(&engine), amounts(("6.0", "0.0", "6.0")));
+    // deposits are disputed the same way under every policy
+    engine.apply(transaction(TransactionType::Deposit, 1, 3, Some("2.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 3, None)).unwrap();
+    assert_eq!(balances(&engine), amounts(("6.0", "2.0", "8.0")));
+}
+
+#[test]
+pub fn negative_available_under_dispute() {
+    // deposit, spend most of it, then dispute the deposit
+    let records = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,8.0\ndispute,1,1,\n";
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut report).unwrap();
+    // forbidden by default, the dispute is refused and nothing moves
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    assert!(report.contains("insufficient_funds"));
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("2.0").unwrap());
+    assert!(!engine.account(1).unwrap().flagged);
+    let config = EngineConfig { negative_available: NegativeAvailablePolicy::AllowUnderDispute, ..EngineConfig::default() };
+    let mut engine = PaymentsEngine::with_config(config);
+    let mut fraud = FraudLog::new(Vec::new());
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut fraud).unwrap();
+    let client = engine.account(1).unwrap();
+    assert_eq!((client.available, client.held, client.total), (Amount::parse_signed("-8.0").unwrap(), Amount::from_str("10.0").unwrap(), Amount::from_str("2.0").unwrap()));
+    assert!(client.flagged);
+    assert_eq!(String::from_utf8(fraud.into_inner()).unwrap(), "[WARNING]: Client 1 is flagged for review at records record #3: their available balance is negative under a dispute of tx 1.\n");
+    // withdrawals still can't overdraw, negative or not
+    assert!(matches!(engine.apply(transaction(TransactionType::Withdrawal, 1, 3, Some("1.0"))).unwrap_err(), EngineError::InsufficientFunds { .. }));
+    // the flag survives a snapshot, and so do the negative balances
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let restored = read_snapshot(snapshot.as_slice(), config).unwrap();
+    assert!(restored.account(1).unwrap().flagged);
+    assert_eq!(restored.account(1).unwrap().available, Amount::parse_signed("-8.0").unwrap());
+    // the chargeback takes the deposit back out of total, which goes negative too, and locks the account
+    engine.apply(transaction(TransactionType::Chargeback, 1, 1, None)).unwrap();
+    let client = engine.account(1).unwrap();
+    assert_eq!((client.available, client.held, client.total), (Amount::parse_signed("-8.0").unwrap(), Amount::ZERO, Amount::parse_signed("-8.0").unwrap()));
+    assert!(client.locked && client.flagged);
+    let raw = RawClient::from(client);
+    assert_eq!(raw.total.to_string(), "-8.0");
+    // the accounts csv reads back, but only as an opening balance under the same policy
+    let mut accounts = Vec::new();
+    write_accounts(&engine, AccountOrder::ClientId, OutputFormat::Csv, &mut accounts).unwrap();
+    // the flag is in the accounts output for operators to see
+    assert_eq!(String::from_utf8(accounts.clone()).unwrap(), "client,available,held,total,locked,flagged\n1,-8.0,0.0,-8.0,true,true\n");
+    assert!(read_opening_balances(accounts.as_slice(), EngineConfig::default()).map(|_| ()).unwrap_err().to_string().contains("negative"));
+    let opened = read_opening_balances(accounts.as_slice(), config).unwrap();
+    assert_eq!(opened.account(1).unwrap().total, Amount::parse_signed("-8.0").unwrap());
+    assert!(opened.account(1).unwrap().flagged);
+    // a resolve instead puts the client back where they were
+    let mut engine = PaymentsEngine::with_config(config);
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut ()).unwrap();
+    engine.apply(transaction(TransactionType::Resolve, 1, 1, None)).unwrap();
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("2.0").unwrap());
+}
+
+#[test]
+pub fn transfers() {
+    let transfer = |client: u16, tx: u32, to: Option<u16>, amount: &str| Transaction { to, ..transaction(TransactionType::Transfer, client, tx, Some(amount)) };
+    let balances = |engine: &PaymentsEngine, client: u16| engine.account(client).map(|client| (client.available.to_string(), client.held.to_string(), client.total.to_string()));
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    // the recipient's account is opened by the transfer
+    assert_eq!(engine.apply(transfer(1, 2, Some(2), "4.0")).unwrap(), Outcome::Transferred(Amount::from_str("4.0").unwrap()));
+    assert_eq!(balances(&engine, 1), Some(("6.0".to_string(), "0.0".to_string(), "6.0".to_string())));
+    assert_eq!(balances(&engine, 2), Some(("4.0".to_string(), "0.0".to_string(), "4.0".to_string())));
+    assert_eq!(engine.sorted_accounts(AccountOrder::FirstSeen).iter().map(|client| client.client).collect::<Vec<u16>>(), vec![1, 2]);
+    assert_eq!(engine.apply(transfer(1, 2, Some(2), "1.0")).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 2 });
+    // a transfer that can't go through on either side leaves both accounts as they were
+    assert!(matches!(engine.apply(transfer(1, 3, Some(2), "7.0")).unwrap_err(), EngineError::InsufficientFunds { client: 1, .. }));
+    assert_eq!(engine.apply(transfer(1, 3, None, "1.0")).unwrap_err(), EngineError::InvalidDestination { client: 1, tx: 3 });
+    assert_eq!(engine.apply(transfer(1, 3, Some(1), "1.0")).unwrap_err(), EngineError::InvalidDestination { client: 1, tx: 3 });
+    engine.apply(transaction(TransactionType::Deposit, 3, 4, Some("922337203685477.5807"))).unwrap();
+    assert!(matches!(engine.apply(transfer(1, 5, Some(3), "1.0")).unwrap_err(), EngineError::Overflow { client: 3, .. }));
+    assert_eq!(balances(&engine, 1), Some(("6.0".to_string(), "0.0".to_string(), "6.0".to_string())));
+    assert_eq!(engine.account(3).unwrap().total, Amount::from_str("922337203685477.5807").unwrap());
+    // and a frozen account can't send or receive
+    engine.apply_trusted(transaction(TransactionType::Lock, 3, 6, None)).unwrap();
+    assert_eq!(engine.apply(transfer(1, 7, Some(3), "1.0")).unwrap_err(), EngineError::AccountLocked { client: 3, tx: 7 });
+    assert_eq!(engine.apply(transfer(3, 7, Some(1), "1.0")).unwrap_err(), EngineError::AccountLocked { client: 3, tx: 7 });
+    assert_eq!(balances(&engine, 1), Some(("6.0".to_string(), "0.0".to_string(), "6.0".to_string())));
+    // only the sender can dispute a transfer, the recipient holds the funds while it's disputed
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 2, 2, None)).unwrap_err(), EngineError::ClientMismatch { client: 2, tx: 2, owner: 1 });
+    engine.apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+    assert_eq!(balances(&engine, 2), Some(("0.0".to_string(), "4.0".to_string(), "4.0".to_string())));
+    assert_eq!(balances(&engine, 1), Some(("6.0".to_string(), "0.0".to_string(), "6.0".to_string())));
+    engine.apply(transaction(TransactionType::Resolve, 1, 2, None)).unwrap();
+    assert_eq!(balances(&engine, 2), Some(("4.0".to_string(), "0.0".to_string(), "4.0".to_string())));
+    assert_eq!(engine.transaction(2).unwrap().state, DisputeState::Resolved);
+    // a chargeback returns the funds to the sender and locks the recipient
+    let mut engine = PaymentsEngine::new();
+    let records = "type,client,tx,amount,to\ndeposit,1,1,10.0,\ntransfer,1,2,4.0,2\ndispute,1,2,,\nchargeback,1,2,,\ndeposit,2,3,1.0,\n";
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut report).unwrap();
+    assert_eq!(balances(&engine, 1), Some(("10.0".to_string(), "0.0".to_string(), "10.0".to_string())));
+    assert_eq!(balances(&engine, 2), Some(("0.0".to_string(), "0.0".to_string(), "0.0".to_string())));
+    assert!(engine.account(2).unwrap().locked && !engine.account(1).unwrap().locked);
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    assert!(report.contains("account_locked") && report.lines().count() == 2);
+    // the recipient's statement has the transfer and its dispute, with their own balances
+    let mut statement = Statement::new(Vec::new(), 2);
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut PaymentsEngine::new(), &mut statement).unwrap();
+    let statement = String::from_utf8(statement.into_inner().unwrap()).unwrap();
+    let rows: Vec<TraceRecord> = csv::Reader::from_reader(statement.as_bytes()).deserialize().map(|record| record.unwrap()).collect();
+    assert_eq!(rows.iter().map(|row| row.outcome.as_str()).collect::<Vec<&str>>(), vec!["transferred", "disputed", "charged_back", "account_locked"]);
+    assert_eq!(rows[1].held, Some(Amount::from_str("4.0").unwrap()));
+}
+
+#[test]
+pub fn transfer_snapshots_and_threads() {
+    let records = "type,client,tx,amount,to\ndeposit,1,1,10.0,\ntransfer,1,2,4.0,2\ndispute,1,2,,\n";
+    let mut engine = PaymentsEngine::new();
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut ()).unwrap();
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let mut restored = read_snapshot(snapshot.as_slice(), EngineConfig::default()).unwrap();
+    restored.apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap();
+    assert_eq!(restored.account(1).unwrap().available, Amount::from_str("10.0").unwrap());
+    assert!(restored.account(2).unwrap().locked);
+    let snapshot = String::from_utf8(snapshot).unwrap();
+    let restore = |text: &str| read_snapshot(text.as_bytes(), EngineConfig::default()).map(|_| ()).unwrap_err().to_string();
+    assert!(restore(&snapshot.replace("\"to\":2", "\"to\":1")).contains("without a recipient"));
+    assert!(restore(&snapshot.replace("\"to\":2", "\"to\":9")).contains("without a recipient"));
+    // threads can't see each other's accounts, so transfers are refused rather than split
+    let path = std::env::temp_dir().join(format!("transfer_threads_{}.csv", std::process::id()));
+    fs::write(&path, records).unwrap();
+    let input: OsString = path.clone().into_os_string();
+    let error = process_parallel(std::slice::from_ref(&input), None, PaymentsEngine::new(), 2, &mut FraudLog::new(io::sink())).map(|_| ()).unwrap_err().to_string();
+    fs::remove_file(&path).unwrap();
+    assert!(error.contains("record #2 is a transfer"));
+    assert!(process_parallel(&[], None, engine, 2, &mut FraudLog::new(io::sink())).map(|_| ()).unwrap_err().to_string().contains("can't be split"));
+}
+
+#[test]
+pub fn only_transfers_have_a_recipient() {
+    // a stray recipient on anything but a transfer is a parse error
+    let records = "type,client,tx,amount,to\ndeposit,1,1,5.0,2\ndeposit,1,2,5.0,\ndispute,1,2,,2\n";
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new(Vec::new());
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut report).unwrap();
+    let report = String::from_utf8(report.into_inner().unwrap()).unwrap();
+    let mut reader = csv::Reader::from_reader(report.as_bytes());
+    let rejected: Vec<(u64, String)> = reader.deserialize::<RejectedRecord>().map(|record| record.unwrap()).map(|r| (r.record, r.reason)).collect();
+    assert_eq!(rejected, vec![(1, "parse_error".to_string()), (3, "parse_error".to_string())]);
+    // and a transaction built with one anyway isn't remembered with it, so the snapshot can be restored
+    let mut stray = transaction(TransactionType::Deposit, 1, 3, Some("1.0"));
+    stray.to = Some(2);
+    engine.apply(stray).unwrap();
+    assert_eq!(engine.transaction(3).unwrap().to, None);
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let restored = read_snapshot(snapshot.as_slice(), EngineConfig::default()).unwrap();
+    assert_eq!(restored.account(1).unwrap().total, Amount::from_str("6.0").unwrap());
+    assert!(restored.account(2).is_none());
+}
+
+#[test]
+pub fn fees_and_interest() {
+    let mut engine = PaymentsEngine::new();
+    // a client can't charge or pay themselves, the platform posts from a trusted source
+    assert_eq!(engine.apply(transaction(TransactionType::Interest, 1, 1, Some("5.0"))).unwrap_err(), EngineError::Untrusted { client: 1, tx: 1 });
+    assert!(engine.account(1).is_none());
+    engine.apply(transaction(TransactionType::Deposit, 1, 2, Some("10.0"))).unwrap();
+    assert_eq!(engine.apply_trusted(transaction(TransactionType::Fee, 1, 3, Some("1.5"))).unwrap(), Outcome::FeeCharged(Amount::from_str("1.5").unwrap()));
+    assert_eq!(engine.apply_trusted(transaction(TransactionType::Interest, 1, 4, Some("0.25"))).unwrap(), Outcome::InterestPaid(Amount::from_str("0.25").unwrap()));
+    let client = engine.account(1).unwrap();
+    assert_eq!((client.available, client.total), (Amount::from_str("8.75").unwrap(), Amount::from_str("8.75").unwrap()));
+    // a fee can't overdraw and its tx id can't be reused, the same as a withdrawal
+    assert!(matches!(engine.apply_trusted(transaction(TransactionType::Fee, 1, 5, Some("9.0"))).unwrap_err(), EngineError::InsufficientFunds { .. }));
+    assert_eq!(engine.apply_trusted(transaction(TransactionType::Interest, 1, 2, Some("1.0"))).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 2 });
+    // and neither can be disputed by the client
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 3, None)).unwrap_err(), EngineError::NotDisputable { client: 1, tx: 3 });
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 4, None)).unwrap_err(), EngineError::NotDisputable { client: 1, tx: 4 });
+    assert_eq!(engine.transaction(3).unwrap().state, DisputeState::Processed);
+    // unlike an admin transaction they don't get past a frozen account
+    engine.apply_trusted(transaction(TransactionType::Lock, 1, 6, None)).unwrap();
+    assert_eq!(engine.apply_trusted(transaction(TransactionType::Fee, 1, 7, Some("1.0"))).unwrap_err(), EngineError::AccountLocked { client: 1, tx: 7 });
+    // a statement keeps them in their own section, in the order they were applied
+    let client_records = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,2.0\n";
+    let platform_records = "type,client,tx,amount,reason\nfee,1,3,0.02,fee on withdrawal 2\ninterest,1,4,0.1,\n";
+    let mut engine = PaymentsEngine::new();
+    let mut statement = Statement::new(Vec::new(), 1);
+    read_transactions(client_records.as_bytes(), InputFormat::Csv, "client", &mut engine, &mut statement).unwrap();
+    read_trusted_transactions(platform_records.as_bytes(), InputFormat::Csv, "platform", &mut engine, &mut statement).unwrap();
+    let statement = String::from_utf8(statement.into_inner().unwrap()).unwrap();
+    let rows: Vec<StatementRecord> = csv::Reader::from_reader(statement.as_bytes()).deserialize().map(|row| row.unwrap()).collect();
+    let sections: Vec<(StatementSection, &str)> = rows.iter().map(|row| (row.section, row.outcome.as_str())).collect();
+    assert_eq!(sections, vec![
+        (StatementSection::Activity, "deposited"),
+        (StatementSection::Activity, "withdrawn"),
+        (StatementSection::FeesAndInterest, "fee_charged"),
+        (StatementSection::FeesAndInterest, "interest_paid"),
+    ]);
+    assert_eq!(rows[3].available, Some(Amount::from_str("8.08").unwrap()));
+}
+
+#[test]
+pub fn fee_schedule_batch() {
+    let tier = |from: &str, percent: &str| FeeTier { from: Amount::from_str(from).unwrap(), percent: Amount::from_str(percent).unwrap() };
+    // tiers can come in any order
+    let schedule = read_fee_schedule("from,percent\n1000,0.5\n10,1.5\n".as_bytes()).unwrap();
+    assert_eq!(schedule.tiers(), &[tier("10", "1.5"), tier("1000", "0.5")]);
+    let fee = |amount: &str| schedule.fee(Amount::from_str(amount).unwrap()).to_string();
+    assert_eq!(fee("9.9999"), "0.0");
+    assert_eq!(fee("10"), "0.15");
+    assert_eq!(fee("999.99"), "14.9999");
+    assert_eq!(fee("1000"), "5.0");
+    // rounded to the nearest ten-thousandth, halves up
+    assert_eq!(fee("10.0003"), "0.15");
+    assert_eq!(fee("10.001"), "0.15");
+    assert_eq!(fee("10.01"), "0.1502");
+    assert!(FeeSchedule::new(vec![tier("1", "100.0001")]).unwrap_err().contains("more than 100%"));
+    assert!(FeeSchedule::new(vec![tier("1", "1"), tier("1", "2")]).unwrap_err().contains("more than one tier from 1.0"));
+    assert!(read_fee_schedule("from,percent\n1,-1\n".as_bytes()).map(|_| ()).unwrap_err().to_string().contains("#1"));
+    // only accepted withdrawals are charged, and the fees count up from the first tx id
+    let records = "type,client,tx,amount\ndeposit,1,1,2000.0\nwithdrawal,1,2,1500.0\nwithdrawal,1,3,1000.0\nwithdrawal,2,4,50.0\nwithdrawal,1,5,5.0\n";
+    let mut engine = PaymentsEngine::new();
+    let mut batch = FeeBatch::new(Vec::new(), schedule, 100);
+    read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut engine, &mut batch).unwrap();
+    let batch = batch.into_inner().unwrap();
+    assert_eq!(String::from_utf8(batch.clone()).unwrap(), "type,client,tx,amount,reason\nfee,1,100,7.5,fee on withdrawal 2\n");
+    // the batch is posted from a trusted input on the next run
+    read_trusted_transactions(batch.as_slice(), InputFormat::Csv, "fees", &mut engine, &mut ()).unwrap();
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("487.5").unwrap());
+    // and runs out rather than wrapping around
+    let mut batch = FeeBatch::new(Vec::new(), FeeSchedule::new(vec![tier("0", "1")]).unwrap(), u32::MAX);
+    let records = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,1.0\nwithdrawal,1,3,1.0\n";
+    let error = read_transactions(records.as_bytes(), InputFormat::Csv, "records", &mut PaymentsEngine::new(), &mut batch).unwrap_err();
+    assert!(error.to_string().contains("Ran out of tx ids"));
+    // the command needs both the schedule and where to start the tx ids
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<OsString>>();
+    match parse_command(args(&["fees", "--schedule", "fees.csv", "--first-tx", "100", "test_data/mixed_test.csv"])).unwrap() {
+        Command::Fees { schedule, first_tx, arguments } => {
+            assert_eq!((schedule, first_tx), (OsString::from("fees.csv"), 100));
+            assert_eq!(arguments.inputs, vec![OsString::from("test_data/mixed_test.csv")]);
+        },
+        command => panic!("expected a fees command, got {:?}", command),
+    }
+    assert!(parse_command(args(&["fees", "--first-tx", "100", "test_data/mixed_test.csv"])).unwrap_err().to_string().contains("--schedule is required"));
+    assert!(parse_command(args(&["fees", "--schedule", "fees.csv", "--first-tx", "-1", "test_data/mixed_test.csv"])).is_err());
+    assert!(parse_command(args(&["fees", "--schedule", "fees.csv", "--first-tx", "1", "--journal", "journal.log"])).is_err());
+}
+
+#[test]
+pub fn authorize_capture_void() {
+    let balances = |engine: &PaymentsEngine| {
+        let client = engine.account(1).unwrap();
+        (client.available.to_string(), client.held.to_string(), client.total.to_string())
+    };
+    let expect = |available: &str, held: &str, total: &str| (available.to_string(), held.to_string(), total.to_string());
+    let mut engine = PaymentsEngine::new();
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    // an authorization holds the funds, and can't hold more than is available
+    assert!(matches!(engine.apply(transaction(TransactionType::Authorize, 1, 2, Some("10.5"))).unwrap_err(), EngineError::InsufficientFunds { .. }));
+    assert_eq!(engine.apply(transaction(TransactionType::Authorize, 1, 2, Some("6.0"))).unwrap(), Outcome::Authorized(Amount::from_str("6.0").unwrap()));
+    assert_eq!(balances(&engine), expect("4.0", "6.0", "10.0"));
+    assert_eq!(engine.apply(transaction(TransactionType::Authorize, 1, 2, Some("1.0"))).unwrap_err(), EngineError::DuplicateTransaction { client: 1, tx: 2 });
+    // a partial capture takes what was captured out of total, and the rest of the authorization goes back to available
+    assert!(matches!(engine.apply(transaction(TransactionType::Capture, 1, 2, Some("6.5"))).unwrap_err(), EngineError::ExceedsAuthorization { .. }));
+    assert_eq!(engine.apply(transaction(TransactionType::Capture, 2, 2, None)).unwrap_err(), EngineError::ClientMismatch { client: 2, tx: 2, owner: 1 });
+    assert_eq!(engine.apply(transaction(TransactionType::Capture, 1, 2, Some("2.5"))).unwrap(), Outcome::Captured(Amount::from_str("2.5").unwrap()));
+    assert_eq!(balances(&engine), expect("7.5", "0.0", "7.5"));
+    assert!(!engine.account(1).unwrap().locked);
+    // it can only be settled once
+    assert_eq!(engine.apply(transaction(TransactionType::Capture, 1, 2, None)).unwrap_err(), EngineError::AlreadySettled { client: 1, tx: 2 });
+    assert_eq!(engine.apply(transaction(TransactionType::Void, 1, 2, None)).unwrap_err(), EngineError::AlreadySettled { client: 1, tx: 2 });
+    // a capture without an amount takes all of it, a void releases all of it
+    engine.apply(transaction(TransactionType::Authorize, 1, 3, Some("2.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Authorize, 1, 4, Some("3.0"))).unwrap();
+    assert_eq!(balances(&engine), expect("2.5", "5.0", "7.5"));
+    assert_eq!(engine.apply(transaction(TransactionType::Capture, 1, 3, None)).unwrap(), Outcome::Captured(Amount::from_str("2.0").unwrap()));
+    assert_eq!(engine.apply(transaction(TransactionType::Void, 1, 4, None)).unwrap(), Outcome::Voided(Amount::from_str("3.0").unwrap()));
+    assert_eq!(balances(&engine), expect("5.5", "0.0", "5.5"));
+    // authorizations are settled by capturing or voiding them, not by the dispute lifecycle, and only they can be captured or voided
+    engine.apply(transaction(TransactionType::Authorize, 1, 5, Some("1.0"))).unwrap();
+    assert_eq!(engine.apply(transaction(TransactionType::Dispute, 1, 5, None)).unwrap_err(), EngineError::NotDisputable { client: 1, tx: 5 });
+    assert_eq!(engine.apply(transaction(TransactionType::Resolve, 1, 5, None)).unwrap_err(), EngineError::NotDisputed { client: 1, tx: 5 });
+    assert_eq!(engine.apply(transaction(TransactionType::Chargeback, 1, 5, None)).unwrap_err(), EngineError::NotDisputed { client: 1, tx: 5 });
+    assert_eq!(engine.apply(transaction(TransactionType::Void, 1, 1, None)).unwrap_err(), EngineError::UnknownTransaction { client: 1, tx: 1 });
+    assert_eq!(balances(&engine), expect("4.5", "1.0", "5.5"));
+    // held funds from a dispute and from an authorization are kept apart
+    engine.apply(transaction(TransactionType::Deposit, 1, 6, Some("2.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Dispute, 1, 6, None)).unwrap();
+    engine.apply(transaction(TransactionType::Void, 1, 5, None)).unwrap();
+    assert_eq!(balances(&engine), expect("5.5", "2.0", "7.5"));
+}
+
+#[test]
+pub fn authorization_expiry() {
+    let config = EngineConfig { authorization_expiry: Some(2), ..EngineConfig::default() };
+    let mut engine = PaymentsEngine::with_config(config);
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Authorize, 1, 2, Some("4.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Authorize, 1, 3, Some("1.0"))).unwrap();
+    assert_eq!(engine.transaction(2).unwrap().expires, Some(4));
+    // nothing else has an expiry
+    assert_eq!(engine.transaction(1).unwrap().expires, None);
+    // rejected transactions count too
+    assert!(engine.apply(transaction(TransactionType::Withdrawal, 1, 4, Some("50.0"))).is_err());
+    assert_eq!(engine.sequence(), 4);
+    assert_eq!(engine.account(1).unwrap().held, Amount::from_str("5.0").unwrap());
+    // the first authorization's window is over before the next transaction is applied, so it can't be captured any more
+    assert_eq!(engine.apply(transaction(TransactionType::Capture, 1, 2, None)).unwrap_err(), EngineError::AlreadySettled { client: 1, tx: 2 });
+    assert_eq!(engine.transaction(2).unwrap().state, DisputeState::Resolved);
+    assert_eq!(engine.account(1).unwrap().held, Amount::from_str("1.0").unwrap());
+    // the second one is carried over in a snapshot and expires on schedule after the restore
+    let mut snapshot = Vec::new();
+    write_snapshot(&engine, &mut snapshot).unwrap();
+    let mut restored = read_snapshot(snapshot.as_slice(), config).unwrap();
+    assert_eq!(restored.sequence(), 5);
+    restored.apply(transaction(TransactionType::Deposit, 1, 5, Some("1.0"))).unwrap();
+    assert_eq!(restored.account(1).unwrap().held, Amount::ZERO);
+    assert_eq!(restored.account(1).unwrap().available, Amount::from_str("11.0").unwrap());
+    // and a snapshot can't give one to anything but an authorization
+    let text = String::from_utf8(snapshot).unwrap();
+    let deposit = text.lines().find(|line| line.contains(r#""tx":1,"#)).unwrap();
+    let error = read_snapshot(text.replace(deposit, &deposit.replace('}', r#","expires":9}"#)).as_bytes(), config).map(|_| ()).unwrap_err().to_string();
+    assert!(error.contains("only authorizations have one"));
+    // a frozen account's authorizations still expire
+    let mut engine = PaymentsEngine::with_config(config);
+    engine.apply(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Authorize, 1, 2, Some("4.0"))).unwrap();
+    engine.apply_trusted(transaction(TransactionType::Lock, 1, 3, None)).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 2, 4, Some("1.0"))).unwrap();
+    engine.apply(transaction(TransactionType::Deposit, 2, 5, Some("1.0"))).unwrap();
+    assert_eq!(engine.account(1).unwrap().available, Amount::from_str("10.0").unwrap());
+    // every shard would count its own transactions, so expiry and threads don't mix
+    assert!(process_parallel(&[], None, PaymentsEngine::with_config(config), 2, &mut FraudLog::new(io::sink())).map(|_| ()).unwrap_err().to_string().contains("expire"));
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<OsString>>();
+    assert_eq!(process_arguments(args(&["--authorization-expiry", "100", "test_data/mixed_test.csv"])).config().authorization_expiry, Some(100));
+    assert!(parse_command(args(&["--authorization-expiry", "100", "--threads", "2", "test_data/mixed_test.csv"])).is_err());
+}
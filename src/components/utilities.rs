@@ -1,35 +1,40 @@
 use std::error::Error;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::env::args_os;
-use std::path::Path;
-#[allow(unused_imports)]
-use std::fs::{File, OpenOptions};
-#[allow(unused_imports)]
-use std::io::{Write, BufReader, stdout};
-use csv::{ReaderBuilder, Writer, Trim};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, BufRead, BufReader, BufWriter, ErrorKind, stdin, stdout};
+use std::mem;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use csv::{Reader, ReaderBuilder, StringRecord, Writer, Trim};
 use rand::{thread_rng, Rng};
-use math::round;
+use serde_json::Value;
 
 // LOCAL
-use crate::components::data_structures::{Client, RawClient, Transaction, RawTransaction, TransactionType};
+use crate::components::data_structures::{Amount, Client, RawClient, Transaction, RawTransaction, TransactionType, PaymentsEngine, Outcome, EngineError, AccountOrder, ProcessedRecord, RecordSink, FraudLog, AccountDifference, TransactionRecord, TransactionIndex, DisputeState, DisputeEvent, EngineConfig, TxOwners, InputFormat, OutputFormat, SnapshotHeader, RawTransactionRecord, JournalEntry, Journal, WithdrawalDisputePolicy, NegativeAvailablePolicy, FeeSchedule};
+
+// the input name that means "read from STDIN"
+pub const STDIN: &str = "-";
 
-#[allow(dead_code)]
 fn generate_deposit() -> RawTransaction {
     RawTransaction {
         transaction_type: TransactionType::Deposit,
         client: 1,
         tx: 1,
-        amount: Some(5.0),
+        amount: Some(Amount::from_units(50000)),
+        reason: None,
+        to: None,
     }
 }
 
-#[allow(dead_code)]
-pub fn generate_test_data() -> Result<(), Box<dyn Error>> {
+// write `rows` random transactions for a handful of clients to `path`, handy for poking at the engine by hand
+pub fn generate_test_data(path: &OsString, rows: u32) -> Result<(), Box<dyn Error>> {
     let first_transaction = generate_deposit();
     let mut test_txs: Vec<RawTransaction> = vec![first_transaction];
     let mut rng = thread_rng();
-    for x in 2..=30 {
+    for x in 2..=rows {
         let type_num: u8 = rng.gen_range(1..=5);
         let tx_type = match type_num {
             1 => TransactionType::Withdrawal,
@@ -56,14 +61,12 @@ pub fn generate_test_data() -> Result<(), Box<dyn Error>> {
             _ => x,
         };
         let tx_amount = match tx_type {
-            // if it's a dispute, resolve, or chargeback we want to reference other txs
+            // amounts are generated directly in ten-thousandths (0.0 up to 99999.0) so they never pick up more than four decimal places
             TransactionType::Withdrawal => {
-                let amount: f64 = rng.gen_range(0.0..99999.0);
-                Some(round::ceil(amount,4))
+                Some(Amount::from_units(rng.gen_range(0..999_990_000)))
             },
             TransactionType::Deposit => {
-                let amount: f64 = rng.gen_range(0.0..99999.0);
-                Some(round::ceil(amount,4))
+                Some(Amount::from_units(rng.gen_range(0..999_990_000)))
             },
             _ => None,
         };
@@ -71,231 +74,1012 @@ pub fn generate_test_data() -> Result<(), Box<dyn Error>> {
             RawTransaction {
                 transaction_type: tx_type, 
                 client: client_num,
-                tx: tx,
+                tx,
                 amount: tx_amount,
+                reason: None,
+                to: None,
             }
         );
     }
-    let mut writer = Writer::from_path("test_data.csv")?;
+    let mut writer = Writer::from_path(path)?;
     for tx in test_txs {
         writer.serialize(tx)?;
-        writer.flush()?;
     }
+    writer.flush()?;
     Ok(())
 }
 
-// function to parse input filename from command line
-pub fn parse_args() -> Result<OsString, Box<dyn Error>> {
-    // collect args into vector, I chose OsString to avoid any encoding issues on different platforms
-    let args: Vec<OsString> = args_os().collect();
-    // the first element is the name of the program itself, the second element is the "first argument" - this is convention in major OSes
-    // we check to see if argument is regular file and perform our own error handling with match control flow.
-    let file_path = Path::new(&args[1]);
-    match file_path.is_file() {
-        true => Ok(args[1].clone()),
-        false => Err("file does not exist".into()),
-    }
+pub fn output_accounts(engine: &PaymentsEngine, order: AccountOrder) -> Result<(), Box<dyn Error>> {
+    write_accounts(engine, order, OutputFormat::Csv, stdout()) // write to STDOUT
 }
 
-pub fn output_accounts(client_data: &HashMap<u16, Client>) -> Result<(), Box<dyn Error>> {
-    let mut writer = Writer::from_writer(stdout()); // initialize writer to STDOUT
-    for client in client_data.values() {
-        let raw_client: RawClient = client.into(); // Convert our Client struct into RawClient for writing
-        writer.serialize(raw_client)?; // serialize our RawClient struct into a csv record
-        writer.flush()?; // "flush" to STDOUT
+// write every account in the given format, in a stable order so the output can be diffed between runs
+pub fn write_accounts<W: Write>(engine: &PaymentsEngine, order: AccountOrder, format: OutputFormat, mut destination: W) -> Result<(), Box<dyn Error>> {
+    // Convert our Client structs into RawClients for writing
+    let accounts = engine.sorted_accounts(order).into_iter().map(|client| engine.raw_account(client));
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = Writer::from_writer(destination);
+            for raw_client in accounts {
+                writer.serialize(raw_client)?; // serialize our RawClient struct into a csv record
+            }
+            writer.flush()?; // "flush" to the destination
+        },
+        OutputFormat::Jsonl => {
+            let mut writer = BufWriter::new(destination);
+            for raw_client in accounts {
+                serde_json::to_writer(&mut writer, &raw_client)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut destination, &accounts.collect::<Vec<RawClient>>())?;
+            writeln!(destination)?;
+            destination.flush()?;
+        },
     }
     Ok(())
 }
 
-// first pass at function to process transaction data in chunks
-pub fn process_transaction_data(filename: &OsString, client_data: &mut HashMap<u16, Client>) -> Result<(), Box<dyn Error>> {
-    // this source could be a TcpStream, etc.
-    let transaction_data_file = File::open(filename)?; 
+// open an input for reading, either a file or "-" for STDIN
+pub fn open_input(filename: &OsString) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    match filename == STDIN {
+        true => Ok(Box::new(stdin().lock())),
+        false => Ok(Box::new(File::open(filename)?)),
+    }
+}
+
+// process a single input, either a file or "-" for STDIN, in the format its extension suggests
+// every record is handed to the sink once the engine has seen it, e.g. to write a rejection report
+pub fn process_transaction_data(filename: &OsString, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    process_input(filename, InputFormat::from_path(filename), false, engine, sink)
+}
+
+// the same as process_transaction_data, when the format has been chosen for us
+// a trusted input can also carry admin transactions, see PaymentsEngine::apply_trusted
+pub fn process_input(filename: &OsString, format: InputFormat, trusted: bool, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    let name = filename.to_string_lossy();
+    match trusted {
+        true => read_trusted_transactions(open_input(filename)?, format, &name, engine, sink),
+        false => read_transactions(open_input(filename)?, format, &name, engine, sink),
+    }
+}
+
+// build custom csv reader with our options
+fn csv_reader<R: Read>(source: R) -> Reader<BufReader<R>> {
     // default buffer capacity is 8kb -> BufReader streams in 8kb at a time
-    let buf_reader = BufReader::new(transaction_data_file); 
-    // build custom csv reader with our options
-    let mut reader = ReaderBuilder::new()
-        .trim(Trim::All) // we use Trim::All to avoid any whitespace issues in the input file
-        .from_reader(buf_reader);
-    //let mut record_num: u32 = 0;
-    for record in reader.deserialize() { // this should be ~O(n) where n is the number of Transactions
-        //record_num += 1;
-        // implicit Deserialization from serde
-        let raw_transaction: RawTransaction = record?;
-        // perform conversion of RawTransaction -> Transaction 
-        let transaction: Transaction = raw_transaction.into();
-        let client_id = transaction.client;
-        // get mutable reference to Client, if it doesn't already exist we create it
-        let client = get_or_insert(client_id, client_data)?; // if we have an error here we should probably terminate - something else is going on ;)
-        // Do our processing here
-        match transaction_handler(client, &transaction) {
-            Ok(()) => {
-                //let mut log_file = OpenOptions::new().append(true).open("transactions.log")?;
-                client.transactions.push(transaction); // good transaction - we add it to the list of transactions for that client
-                //let success_msg = format!("[RECORD #{}][SUCCESS]: Transaction processed successfully.", record_num);
-                //write!(log_file, "{}\n", success_msg)?;
-            },
-            Err(_error_msg) => { // do something with potential error messages -> write to error.log
-                //let mut log_file = OpenOptions::new().append(true).open("transactions.log")?;
-                //write!(log_file, "[RECORD #{}]{}\n", record_num, error_msg)?;
-            }, 
+    csv_builder().from_reader(BufReader::new(source))
+}
+
+fn csv_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.trim(Trim::All); // we use Trim::All to avoid any whitespace issues in the input file
+    builder
+}
+
+// implicit Deserialization from serde then conversion of RawTransaction -> Transaction, a record we can't read is rejected like any other bad transaction
+fn parse_record(record: &StringRecord, headers: &StringRecord) -> Result<Transaction, EngineError> {
+    // a short row just leaves the optional columns empty, but we can't tell what the extra fields of a long one were meant to be (empty ones are fine)
+    if record.iter().skip(headers.len()).any(|field| !field.is_empty()) {
+        return Err(EngineError::Parse(format!("found record with {} fields, but the header has {}", record.len(), headers.len())));
+    }
+    record.deserialize::<RawTransaction>(Some(headers))
+        .map_err(|err| EngineError::Parse(err.to_string()))
+        .and_then(Transaction::try_from)
+}
+
+// every record in an input, along with the transaction read from it or the reason it couldn't be read
+// we keep the original row (a csv record, or the whole line for jsonl) so a rejected record can be reported as it was written
+fn transaction_records<'a, R: Read + 'a>(source: R, format: InputFormat) -> Result<ParsedRecords<'a>, Box<dyn Error>> {
+    match format {
+        InputFormat::Csv => {
+            // a row with the wrong number of fields (e.g. "dispute,1,1" without a trailing comma) is still read, and refused if it's missing something,
+            // and one that isn't UTF-8 is refused too, so only a failure to read the input itself stops the run
+            let mut reader = csv_builder().flexible(true).from_reader(BufReader::new(source));
+            let headers = reader.headers()?.clone();
+            Ok(Box::new(reader.into_byte_records().map(move |record| {
+                Ok(match StringRecord::from_byte_record(record?) {
+                    Ok(record) => {
+                        let transaction = parse_record(&record, &headers);
+                        (record, transaction)
+                    },
+                    Err(err) => {
+                        let reason = EngineError::Parse(err.utf8_error().to_string());
+                        (err.into_byte_record().iter().map(String::from_utf8_lossy).collect(), Err(reason))
+                    },
+                })
+            })))
+        },
+        InputFormat::Jsonl => {
+            // blank lines are skipped, the same as blank rows in a csv file
+            let lines = BufReader::new(source).lines().filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+            Ok(Box::new(lines.map(|line| {
+                let line = line?;
+                let line = line.trim();
+                let transaction = serde_json::from_str(line)
+                    .map_err(|err| EngineError::Parse(err.to_string()))
+                    .and_then(transaction_from_json);
+                Ok((StringRecord::from(vec![line]), transaction))
+            })))
+        },
+    }
+}
+
+// how many records the reader collects for a shard before handing them over, sending them one at a time costs more than applying them
+const SHARD_BATCH: usize = 4096;
+
+// a record as it was read, and the transaction read from it
+type ParsedRecord = (StringRecord, Result<Transaction, EngineError>);
+
+// the records of one input, a failure to read the input itself ends it with an error
+type ParsedRecords<'a> = Box<dyn Iterator<Item = Result<ParsedRecord, Box<dyn Error>>> + 'a>;
+
+// a transaction on its way to a shard, along with the reason the reader already refused it, if it did
+type ShardItem = (Transaction, Option<EngineError>);
+
+// what a shard made of a transaction that would claim its tx id (a deposit, withdrawal or authorization): the id, and whether it was accepted
+type ShardDecision = (u32, bool);
+
+// whether a transaction type claims its tx id for its client when it's accepted
+fn claims_id(transaction_type: TransactionType) -> bool {
+    matches!(transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Authorize)
+}
+
+// process inputs, in order, on `shards` worker threads, each owning the clients whose id % shards is its own
+// this thread reads and parses the records and routes each one to its client's shard, so every client's transactions are still applied in order
+// the shards start from engine (e.g. a restored snapshot) and are merged back into one engine at the end,
+// there is no per-record sink because records are applied on other threads, but the reader catches tx ids shared between clients itself and reports them to fraud
+// format is the format of every input, None picks each one's from its extension
+pub fn process_parallel<W: Write>(inputs: &[OsString], format: Option<InputFormat>, engine: PaymentsEngine, shards: usize, fraud: &mut FraudLog<W>) -> Result<PaymentsEngine, Box<dyn Error>> {
+    let config = engine.config;
+    // a transfer's two clients can live on different shards, which can't touch each other's accounts
+    if engine.transactions.values().any(|record| record.transaction_type == TransactionType::Transfer) {
+        return Err("[ERROR]: Transfers can't be split across threads, process these accounts without --threads.".into());
+    }
+    // and every shard would count its own sequence, so authorizations would expire at different times than on one thread
+    if config.authorization_expiry.is_some() || !engine.expiries.is_empty() {
+        return Err("[ERROR]: Authorizations can't expire across threads, process these accounts without --threads.".into());
+    }
+    // the reader carries on from what the engine already knows about tx ids and clients
+    let mut owners = TxOwners::new();
+    for (tx, record) in &engine.transactions {
+        owners.claim(*tx, record.client);
+    }
+    let first_seen = engine.first_seen.clone();
+    // the shards tell the reader which ids they accepted, it's never full so a shard never waits on the reader
+    let (decided, decisions) = mpsc::channel::<Vec<ShardDecision>>();
+    let (senders, workers): (Vec<SyncSender<Vec<ShardItem>>>, Vec<JoinHandle<PaymentsEngine>>) = engine.split(shards.max(1)).into_iter().map(|mut engine| {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<ShardItem>>(16);
+        let decided = decided.clone();
+        let worker = thread::spawn(move || {
+            for batch in receiver {
+                let mut decisions = Vec::new();
+                for (transaction, refused) in batch {
+                    // a refused transaction still opens the client's account, the same as it would in PaymentsEngine::apply
+                    match refused {
+                        Some(_) => engine.open_account(transaction.client),
+                        None => {
+                            let (tx, claims) = (transaction.tx, claims_id(transaction.transaction_type));
+                            let accepted = engine.apply(transaction).is_ok();
+                            if claims {
+                                decisions.push((tx, accepted));
+                            }
+                        },
+                    }
+                }
+                // once the reader has read everything it stops listening, but the batches it already sent still have to be applied
+                if !decisions.is_empty() {
+                    let _ = decided.send(decisions);
+                }
+            }
+            engine
+        });
+        (sender, worker)
+    }).unzip();
+    drop(decided);
+    let first_seen = read_sharded(inputs, format, &senders, owners, first_seen, decisions, fraud);
+    drop(senders); // hanging up lets the workers finish, whether or not the reader got to the end
+    let mut engines = Vec::new();
+    for worker in workers {
+        engines.push(worker.join().map_err(|_| "[ERROR]: A shard worker panicked while applying transactions.")?);
+    }
+    Ok(PaymentsEngine::merge(engines, config, first_seen?))
+}
+
+// read every input and send each transaction to its client's shard, returns the clients in the order they were first seen
+// shards can't see each other's transactions, so tx ids shared between clients are caught here instead:
+// a deposit, withdrawal or authorization reusing another client's id is a duplicate, and anything else naming one is a client mismatch
+// an id only belongs to a client once their shard has accepted the transaction claiming it, the same as on one thread,
+// so a record reaching for an id another client's shard hasn't decided on yet waits for that decision
+fn read_sharded<W: Write>(inputs: &[OsString], format: Option<InputFormat>, senders: &[SyncSender<Vec<ShardItem>>], mut owners: TxOwners, mut first_seen: Vec<u16>, decisions: Receiver<Vec<ShardDecision>>, fraud: &mut FraudLog<W>) -> Result<Vec<u16>, Box<dyn Error>> {
+    let mut known = vec![false; u16::MAX as usize + 1];
+    for client in &first_seen {
+        known[*client as usize] = true;
+    }
+    let mut batches: Vec<Vec<ShardItem>> = senders.iter().map(|_| Vec::with_capacity(SHARD_BATCH)).collect();
+    let send = |sender: &SyncSender<Vec<ShardItem>>, batch: Vec<ShardItem>| sender.send(batch).map_err(|_| "[ERROR]: A shard worker stopped before the input was finished.");
+    let shard_of = |client: u16| client as usize % senders.len();
+    for input in inputs {
+        let name = input.to_string_lossy();
+        let records = transaction_records(open_input(input)?, format.unwrap_or_else(|| InputFormat::from_path(input)))?;
+        for (record_num, record) in (1u64..).zip(records) {
+            // records we can't read are skipped, the same as in process_transaction_data
+            let Ok(transaction) = record?.1 else { continue };
+            let (client, tx) = (transaction.client, transaction.tx);
+            if transaction.transaction_type == TransactionType::Transfer {
+                return Err(format!("[ERROR]: {} record #{} is a transfer, transfers can't be split across threads, process this input without --threads.", name, record_num).into());
+            }
+            // another client's claim on the id is still out (as far as we've heard), so hand over everything their shard hasn't got yet and wait until it's settled
+            while let Some(claimant) = owners.claimant(tx).filter(|claimant| *claimant != client) {
+                let shard = shard_of(claimant);
+                if !batches[shard].is_empty() {
+                    send(&senders[shard], mem::replace(&mut batches[shard], Vec::with_capacity(SHARD_BATCH)))?;
+                }
+                let batch = decisions.recv().map_err(|_| "[ERROR]: A shard worker stopped before the input was finished.")?;
+                for (tx, accepted) in batch {
+                    owners.settle(tx, accepted);
+                }
+            }
+            let refused = match (transaction.transaction_type, owners.owner(tx)) {
+                // a replayed id is refused before the account is opened, like in PaymentsEngine::apply
+                (transaction_type, Some(owner)) if claims_id(transaction_type) && owner != client => continue,
+                (transaction_type, None) if claims_id(transaction_type) => {
+                    owners.propose(tx, client);
+                    None
+                },
+                (TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback | TransactionType::Capture | TransactionType::Void, Some(owner)) if owner != client => {
+                    let error = EngineError::ClientMismatch { client, tx, owner };
+                    fraud.check(&name, record_num, Some(&transaction), &Err(error.clone()), None)?;
+                    Some(error)
+                },
+                // the client's own id, an id nobody has (the shard says it's unknown), or something the shard refuses on its own (e.g. an admin transaction)
+                _ => None,
+            };
+            if !known[client as usize] {
+                known[client as usize] = true;
+                first_seen.push(client);
+            }
+            let shard = shard_of(client);
+            batches[shard].push((transaction, refused));
+            if batches[shard].len() == SHARD_BATCH {
+                send(&senders[shard], mem::replace(&mut batches[shard], Vec::with_capacity(SHARD_BATCH)))?;
+                // catch up on what the shards have decided now and then, so claims that were settled long ago don't keep us waiting
+                for (tx, accepted) in decisions.try_iter().flatten() {
+                    owners.settle(tx, accepted);
+                }
+            }
         }
     }
-    Ok(())
+    for (sender, batch) in senders.iter().zip(batches) {
+        send(sender, batch)?;
+    }
+    fraud.finish()?;
+    Ok(first_seen)
 }
 
-// gets mutable ref to Client or inserts new client and gets mutable ref to THAT client
-pub fn get_or_insert<'a>(id: u16, client_data: &'a mut HashMap<u16, Client>) -> Result<&'a mut Client, Box<dyn Error>> {
-    let error_msg = "[ERROR]: Issue retrieving value from HashMap";
-    if client_data.contains_key(&id) {
-        client_data.get_mut(&id).ok_or(error_msg.into())
-    } else {
-        client_data.insert(id, Client::new(id));    
-        client_data.get_mut(&id).ok_or(error_msg.into())
+// read a transaction from a JSON object shaped like RawTransaction, the amount can be written as a string or a number
+// a number is taken as the exact text it was written with (serde_json's arbitrary_precision), so it never passes through f64 either
+// string values are trimmed, the same as csv fields
+pub fn transaction_from_json(mut value: Value) -> Result<Transaction, EngineError> {
+    if let Value::Object(fields) = &mut value {
+        for field in fields.values_mut() {
+            if let Value::String(text) = field {
+                *text = text.trim().to_string();
+            }
+        }
+    }
+    if let Some(amount) = value.get_mut("amount") {
+        if let Value::Number(number) = amount {
+            *amount = Value::String(number.to_string());
+        }
     }
+    serde_json::from_value::<RawTransaction>(value)
+        .map_err(|err| EngineError::Parse(err.to_string()))
+        .and_then(Transaction::try_from)
 }
 
-// entrypoint for different transaction types
-pub fn transaction_handler(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    client.is_frozen()?; // Assumption: if client account is frozen we do nothing.
-    match incoming_tx.transaction_type {
-        TransactionType::Deposit => {
-            handle_deposit(client, incoming_tx)?;
-        }, 
-        TransactionType::Withdrawal => {
-            handle_withdrawal(client, incoming_tx)?;
+// process transaction data in chunks from any source, this could be a file, STDIN, a TcpStream, or a buffer in a test
+// name is only used to say where a record came from
+pub fn read_transactions<R: Read>(source: R, format: InputFormat, name: &str, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    read_records(source, format, name, false, engine, sink)
+}
+
+// the same as read_transactions, for a trusted source whose admin transactions (unlock, lock, adjustment) are applied too
+pub fn read_trusted_transactions<R: Read>(source: R, format: InputFormat, name: &str, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    read_records(source, format, name, true, engine, sink)
+}
+
+fn read_records<R: Read>(source: R, format: InputFormat, name: &str, trusted: bool, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    // we read plain records first and deserialize them ourselves, so the original row is still around if it gets rejected
+    for (record_num, record) in (1u64..).zip(transaction_records(source, format)?) { // this should be ~O(n) where n is the number of Transactions
+        // a failure to read the file itself stops processing
+        apply_record(record?, name, record_num, trusted, engine, sink)?;
+    }
+    sink.finish()
+}
+
+// the same as read_transactions, for an engine shared between threads (e.g. by the server)
+// the lock is only held for one record at a time, so a slow stream doesn't hold up everyone else
+pub fn read_shared_transactions<R: Read>(source: R, format: InputFormat, name: &str, engine: &Mutex<PaymentsEngine>, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    for (record_num, record) in (1u64..).zip(transaction_records(source, format)?) {
+        let record = record?;
+        let mut engine = engine.lock().map_err(|_| "[ERROR]: Another thread panicked while applying a transaction, the accounts can't be trusted.")?;
+        apply_record(record, name, record_num, false, &mut engine, sink)?;
+    }
+    sink.finish()
+}
+
+// apply a single record to the engine and hand the result to the sink
+fn apply_record((record, transaction): ParsedRecord, name: &str, record_num: u64, trusted: bool, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<(), Box<dyn Error>> {
+    // Do our processing here
+    let result = match &transaction {
+        Ok(transaction) if trusted => engine.apply_trusted(transaction.clone()),
+        Ok(transaction) => engine.apply(transaction.clone()),
+        Err(error) => Err(error.clone()),
+    };
+    sink.record(&ProcessedRecord {
+        input: name,
+        record: record_num,
+        row: &record,
+        transaction: transaction.as_ref().ok(),
+        result: &result,
+        trusted,
+    }, engine)
+}
+
+// read every record without applying it, only records that can't be read as a transaction are reported to the sink
+// returns the number of records that couldn't be read
+pub fn validate_transactions<R: Read>(source: R, format: InputFormat, name: &str, sink: &mut dyn RecordSink) -> Result<u64, Box<dyn Error>> {
+    let engine = PaymentsEngine::new(); // stays empty, it's only here because sinks get to look at the engine
+    let mut invalid = 0;
+    for (record_num, record) in (1u64..).zip(transaction_records(source, format)?) {
+        let (record, transaction) = record?;
+        if let Err(error) = transaction {
+            invalid += 1;
+            sink.record(&ProcessedRecord {
+                input: name,
+                record: record_num,
+                row: &record,
+                transaction: None,
+                result: &Err(error),
+                trusted: false,
+            }, &engine)?;
+        }
+    }
+    sink.finish()?;
+    Ok(invalid)
+}
+
+// read an accounts file in the shape output_accounts writes
+pub fn read_accounts<R: Read>(source: R) -> Result<Vec<RawClient>, Box<dyn Error>> {
+    let mut accounts = Vec::new();
+    for account in csv_reader(source).deserialize() {
+        accounts.push(account?);
+    }
+    Ok(accounts)
+}
+
+// seed an engine with the accounts in an accounts file (the shape output_accounts writes), before any transactions are applied
+// every row has to add up (total == available + held) and every client can only appear once, otherwise the whole file is refused
+pub fn read_opening_balances<R: Read>(source: R, config: EngineConfig) -> Result<PaymentsEngine, Box<dyn Error>> {
+    let mut engine = PaymentsEngine::with_config(config);
+    for (row, account) in (1u64..).zip(read_accounts(source)?) {
+        if account.available.checked_add(account.held) != Some(account.total) {
+            return Err(format!("[ERROR]: Opening balance #{} for client: {} doesn't add up, total: {} should be available: {} + held: {}.", row, account.client, account.total, account.available, account.held).into());
+        }
+        if engine.clients.contains_key(&account.client) {
+            return Err(format!("[ERROR]: Opening balance #{} repeats client: {}.", row, account.client).into());
+        }
+        // a negative balance can only come from a dispute, so it only makes sense where disputes are allowed to cause one
+        let negative = account.available.is_negative() || account.total.is_negative();
+        if negative && config.negative_available == NegativeAvailablePolicy::Forbid {
+            return Err(format!("[ERROR]: Opening balance #{} for client: {} is negative, which needs the allow negative available policy.", row, account.client).into());
+        }
+        engine.first_seen.push(account.client);
+        engine.clients.insert(account.client, Client::from(account));
+    }
+    Ok(engine)
+}
+
+// read a fee schedule, a csv of from,percent tiers (see FeeSchedule)
+pub fn read_fee_schedule<R: Read>(source: R) -> Result<FeeSchedule, Box<dyn Error>> {
+    let mut tiers = Vec::new();
+    for (row, tier) in (1u64..).zip(csv_reader(source).deserialize()) {
+        tiers.push(tier.map_err(|err| format!("[ERROR]: Fee tier #{} can't be read: {}", row, err))?);
+    }
+    Ok(FeeSchedule::new(tiers)?)
+}
+
+// compare two accounts files client by client, the differences come back sorted by client id
+pub fn diff_accounts<L: Read, R: Read>(left: L, right: R) -> Result<Vec<AccountDifference>, Box<dyn Error>> {
+    let (mut left, mut right) = (read_accounts(left)?, read_accounts(right)?);
+    // a file without the flagged column has nobody flagged, so when only one of them has it the other is compared as all false
+    if left.iter().chain(&right).any(|account| account.flagged.is_some()) {
+        for account in left.iter_mut().chain(right.iter_mut()) {
+            account.flagged.get_or_insert(false);
+        }
+    }
+    let mut left: HashMap<u16, RawClient> = left.into_iter().map(|account| (account.client, account)).collect();
+    let mut right: HashMap<u16, RawClient> = right.into_iter().map(|account| (account.client, account)).collect();
+    let mut ids: Vec<u16> = left.keys().chain(right.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let differences = ids.into_iter()
+        .map(|client| AccountDifference { client, left: left.remove(&client), right: right.remove(&client) })
+        .filter(|difference| difference.left != difference.right)
+        .collect();
+    Ok(differences)
+}
+
+// the snapshot format we write, and the only one we can read back
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// write everything the engine needs to carry on where it left off: every account and every disputable transaction with its dispute state
+// the accepted tx ids aren't written separately, they're exactly the ids in the transaction index
+pub fn write_snapshot<W: Write>(engine: &PaymentsEngine, destination: W) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(destination);
+    let mut transactions: Vec<(&u32, &TransactionRecord)> = engine.transactions.iter().collect();
+    transactions.sort_unstable_by_key(|(tx, _)| **tx);
+    let header = SnapshotHeader { version: SNAPSHOT_VERSION, accounts: engine.clients.len() as u64, transactions: transactions.len() as u64, sequence: engine.sequence };
+    serde_json::to_writer(&mut writer, &header)?;
+    writeln!(writer)?;
+    for client in engine.sorted_accounts(AccountOrder::FirstSeen) {
+        serde_json::to_writer(&mut writer, &RawClient::from(client))?;
+        writeln!(writer)?;
+    }
+    for (tx, record) in transactions {
+        serde_json::to_writer(&mut writer, &RawTransactionRecord::from((*tx, record)))?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// build an engine from a snapshot written by write_snapshot, anything that doesn't add up is an error rather than a partial restore
+pub fn read_snapshot<R: Read>(source: R, config: EngineConfig) -> Result<PaymentsEngine, Box<dyn Error>> {
+    let mut lines = BufReader::new(source).lines();
+    let header: SnapshotHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).map_err(|err| format!("[ERROR]: Not a snapshot: {}", err))?,
+        None => return Err("[ERROR]: The snapshot is empty.".into()),
+    };
+    if header.version != SNAPSHOT_VERSION {
+        return Err(format!("[ERROR]: Snapshot version {} is not supported, expected version {}.", header.version, SNAPSHOT_VERSION).into());
+    }
+    let mut engine = PaymentsEngine::with_config(config);
+    let mut read_line = |line_num: u64| -> Result<String, Box<dyn Error>> {
+        match lines.next() {
+            Some(line) => Ok(line?),
+            None => Err(format!("[ERROR]: The snapshot ends at line {}, its header promised {} accounts and {} transactions.", line_num, header.accounts, header.transactions).into()),
+        }
+    };
+    let mut line_num = 1;
+    for _ in 0..header.accounts {
+        line_num += 1;
+        let account: RawClient = serde_json::from_str(&read_line(line_num)?).map_err(|err| format!("[ERROR]: Snapshot line {} is not an account: {}", line_num, err))?;
+        if engine.clients.contains_key(&account.client) {
+            return Err(format!("[ERROR]: Snapshot line {} repeats client {}.", line_num, account.client).into());
+        }
+        engine.first_seen.push(account.client);
+        engine.clients.insert(account.client, Client::from(account));
+    }
+    for _ in 0..header.transactions {
+        line_num += 1;
+        let record: RawTransactionRecord = serde_json::from_str(&read_line(line_num)?).map_err(|err| format!("[ERROR]: Snapshot line {} is not a transaction: {}", line_num, err))?;
+        if !engine.clients.contains_key(&record.client) {
+            return Err(format!("[ERROR]: Snapshot line {} has tx {} for client {}, who has no account.", line_num, record.tx, record.client).into());
+        }
+        // a transfer has to be to some other client with an account, and nothing else has a recipient at all
+        match (record.transaction_type, record.to) {
+            (TransactionType::Transfer, Some(to)) if to != record.client && engine.clients.contains_key(&to) => (),
+            (TransactionType::Transfer, _) => return Err(format!("[ERROR]: Snapshot line {} has transfer {} without a recipient who has an account.", line_num, record.tx).into()),
+            (_, Some(_)) => return Err(format!("[ERROR]: Snapshot line {} has tx {} with a recipient, only transfers have one.", line_num, record.tx).into()),
+            _ => (),
         }
-        TransactionType::Dispute => {
-            handle_dispute(client, incoming_tx)?;
-        }, 
-        TransactionType::Resolve => {
-            handle_resolve(client, incoming_tx)?;
+        // and only a disputed withdrawal remembers the policy it was disputed under, which has to be one that allows it
+        match (record.transaction_type, record.dispute_policy) {
+            (TransactionType::Withdrawal, Some(WithdrawalDisputePolicy::Reject)) => return Err(format!("[ERROR]: Snapshot line {} has withdrawal {} disputed under the reject policy.", line_num, record.tx).into()),
+            (TransactionType::Withdrawal, _) | (_, None) => (),
+            (_, Some(_)) => return Err(format!("[ERROR]: Snapshot line {} has tx {} with a dispute policy, only withdrawals have one.", line_num, record.tx).into()),
         }
-        TransactionType::Chargeback => {
-            handle_chargeback(client, incoming_tx)?;
+        if engine.transactions.contains_key(&record.tx) {
+            return Err(format!("[ERROR]: Snapshot line {} repeats tx {}.", line_num, record.tx).into());
         }
+        // only an authorization expires
+        if record.expires.is_some() && record.transaction_type != TransactionType::Authorize {
+            return Err(format!("[ERROR]: Snapshot line {} has tx {} with an expiry, only authorizations have one.", line_num, record.tx).into());
+        }
+        // an authorization still open carries on towards its expiry, counted from the restored sequence
+        if let (Some(expires), DisputeState::Disputed) = (record.expires, record.state) {
+            engine.expiries.push(Reverse((expires, record.tx)));
+        }
+        engine.transactions.insert(record.tx, TransactionRecord::from(&record));
+    }
+    engine.sequence = header.sequence;
+    if lines.next().is_some() {
+        return Err(format!("[ERROR]: The snapshot carries on past line {}, where its header said it would end.", line_num).into());
     }
+    Ok(engine)
+}
+
+// write a snapshot to a file, going through a temporary file so a crash part way through never leaves a half written snapshot behind
+pub fn save_snapshot(path: &OsString, engine: &PaymentsEngine) -> Result<(), Box<dyn Error>> {
+    let mut temporary = path.clone();
+    temporary.push(".tmp");
+    write_snapshot(engine, File::create(&temporary)?)?;
+    fs::rename(&temporary, path)?;
     Ok(())
 }
 
-// function to find withdrawal or deposit referenced by tx_id, returning a mutable reference to that original withdrawal or deposit in Vec<Transaction>
-fn find_referenced_transaction<'a>(tx_id: &u32, transactions: &'a mut Vec<Transaction>) -> Result<&'a mut Transaction, Box<dyn Error>> {
-    // find referenced transaction, tx_id should match AND we should have Some(amount)
-    let referenced_transaction = transactions.iter_mut()
-                    .find(|t| t.tx == *tx_id && t.amount.is_some())
-                    .ok_or(format!("[ERROR]: Could not find referenced transaction id: {}", tx_id))?;
-    Ok(&mut *referenced_transaction)
+// rebuild the accounts from a journal written by Journal: every entry is checked against its checksum and sequence number,
+// its transaction is applied again and has to come out the way it was journaled, and then it's handed to the sink like a freshly read record
+// rejected transactions are applied too, since even a refused transaction opens an account for its client
+// returns the sequence number the next entry appended to the journal should get
+pub fn replay_journal<R: Read>(source: R, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<u64, Box<dyn Error>> {
+    let mut sequence = 1;
+    for (line_num, line) in (1u64..).zip(BufReader::new(source).lines()) {
+        let line = line?;
+        let json = match line.split_once(' ') {
+            Some((checksum, json)) if u32::from_str_radix(checksum, 16).ok() == Some(crc32fast::hash(json.as_bytes())) => json,
+            _ => return Err(format!("[ERROR]: Journal line {} is corrupt, its checksum doesn't match.", line_num).into()),
+        };
+        let entry: JournalEntry = serde_json::from_str(json).map_err(|err| format!("[ERROR]: Journal line {} is not a journal entry: {}", line_num, err))?;
+        if entry.sequence != sequence {
+            return Err(format!("[ERROR]: Journal line {} is entry {}, expected entry {}.", line_num, entry.sequence, sequence).into());
+        }
+        let transaction = entry.transaction.map(Transaction::try_from).transpose()
+            .map_err(|err| format!("[ERROR]: Journal line {} has a transaction that can't be read: {}", line_num, err))?;
+        let result = match &transaction {
+            Some(transaction) if entry.trusted => engine.apply_trusted(transaction.clone()),
+            Some(transaction) => engine.apply(transaction.clone()),
+            // nothing to apply, it's only in the journal so the record isn't forgotten
+            None => Err(EngineError::Parse(entry.detail.unwrap_or_default())),
+        };
+        let outcome = match &result {
+            Ok(outcome) => outcome.code(),
+            Err(error) => error.code(),
+        };
+        if outcome != entry.outcome {
+            return Err(format!("[ERROR]: Journal entry {} was {} when it was journaled but {} on replay, the journal doesn't match these accounts.", sequence, entry.outcome, outcome).into());
+        }
+        sink.record(&ProcessedRecord {
+            input: &entry.input,
+            record: entry.record,
+            row: &StringRecord::from(vec![entry.row]),
+            transaction: transaction.as_ref(),
+            result: &result,
+            trusted: entry.trusted,
+        }, engine)?;
+        sequence += 1;
+    }
+    sink.finish()?;
+    Ok(sequence)
+}
+
+// open the journal at path for appending, replaying whatever it already holds into engine (and through sink) first
+// so the journal stays the whole history of the accounts from one run to the next
+pub fn open_journal(path: &OsString, engine: &mut PaymentsEngine, sink: &mut dyn RecordSink) -> Result<Journal<File>, Box<dyn Error>> {
+    let sequence = match File::open(path) {
+        Ok(file) => replay_journal(file, engine, sink)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => 1,
+        Err(err) => return Err(err.into()),
+    };
+    Ok(Journal::new(OpenOptions::new().create(true).append(true).open(path)?, sequence))
+}
+
+// gets mutable ref to Client or inserts new client and gets mutable ref to THAT client
+pub fn get_or_insert(id: u16, client_data: &mut HashMap<u16, Client>) -> &mut Client {
+    client_data.entry(id).or_insert_with(|| Client::new(id))
+}
+
+// entrypoint for different transaction types
+pub fn transaction_handler(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    // Assumption: if client account is frozen we do nothing, unless an operator is acting on it (that's how it gets unlocked)
+    if !incoming_tx.transaction_type.is_admin() {
+        client.is_frozen(incoming_tx.tx)?;
+    }
+    match incoming_tx.transaction_type {
+        TransactionType::Deposit => handle_deposit(client, transactions, incoming_tx),
+        TransactionType::Withdrawal => handle_withdrawal(client, transactions, incoming_tx),
+        TransactionType::Dispute => handle_dispute(client, transactions, config, incoming_tx),
+        TransactionType::Resolve => handle_resolve(client, transactions, config, incoming_tx),
+        TransactionType::Chargeback => handle_chargeback(client, transactions, config, incoming_tx),
+        TransactionType::Unlock => {
+            client.locked = false;
+            Ok(Outcome::Unlocked)
+        },
+        TransactionType::Lock => {
+            client.locked = true;
+            Ok(Outcome::Locked)
+        },
+        TransactionType::Adjustment => handle_adjustment(client, transactions, incoming_tx),
+        TransactionType::Fee | TransactionType::Interest => handle_posting(client, transactions, incoming_tx),
+        TransactionType::Authorize => handle_authorize(client, transactions, incoming_tx),
+        TransactionType::Capture => handle_capture(client, transactions, config, incoming_tx),
+        TransactionType::Void => handle_void(client, transactions, config, incoming_tx),
+        // a transfer needs the recipient's account too, PaymentsEngine::apply sends it to transfer_handler instead
+        TransactionType::Transfer => Err(EngineError::InvalidDestination { client: client.client, tx: incoming_tx.tx }),
+    }
+}
+
+// entrypoint for transfers and the disputes, resolves and chargebacks that reference one, these change two accounts at once
+// every balance on both sides is worked out before either account is touched, so a transfer that fails leaves both as they were
+// the sender's account must already be open, the recipient's is opened once the transfer goes through
+pub fn transfer_handler(clients: &mut HashMap<u16, Client>, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    // a frozen account can't send, receive, or have a transfer disputed
+    get_or_insert(incoming_tx.client, clients).is_frozen(incoming_tx.tx)?;
+    match incoming_tx.transaction_type {
+        TransactionType::Transfer => handle_transfer(clients, transactions, incoming_tx),
+        TransactionType::Dispute => handle_transfer_dispute(clients, transactions, config, incoming_tx, DisputeEvent::Dispute),
+        TransactionType::Resolve => handle_transfer_dispute(clients, transactions, config, incoming_tx, DisputeEvent::Resolve),
+        TransactionType::Chargeback => handle_transfer_dispute(clients, transactions, config, incoming_tx, DisputeEvent::Chargeback),
+        _ => transaction_handler(get_or_insert(incoming_tx.client, clients), transactions, config, incoming_tx),
+    }
+}
+
+// function to find withdrawal or deposit referenced by tx_id, returning a mutable reference to the record we kept for it
+fn find_referenced_transaction<'a>(incoming_tx: &Transaction, transactions: &'a mut TransactionIndex) -> Result<&'a mut TransactionRecord, EngineError> {
+    // constant time lookup by tx id
+    let record = transactions.get_mut(&incoming_tx.tx)
+        .ok_or(EngineError::UnknownTransaction { client: incoming_tx.client, tx: incoming_tx.tx })?;
+    // the referenced transaction must also belong to the client named on the incoming tx, anything else could be an attempt at fraud
+    match record.client == incoming_tx.client {
+        true => Ok(record),
+        false => Err(EngineError::ClientMismatch { client: incoming_tx.client, tx: incoming_tx.tx, owner: record.client }),
+    }
+}
+
+// remember an accepted deposit or withdrawal so it can be disputed later, the engine has already made sure the tx id is new
+fn record_transaction(transactions: &mut TransactionIndex, incoming_tx: &Transaction, amount: Amount) {
+    transactions.insert(incoming_tx.tx, TransactionRecord::new(incoming_tx, amount));
+}
+
+// pull the amount out of a transaction that must have one
+fn required_amount(tx: &Transaction) -> Result<Amount, EngineError> {
+    tx.amount.ok_or(EngineError::MissingAmount { client: tx.client, tx: tx.tx })
+}
+
+// checked add on a balance, in case of overflow
+fn credit(balance: Amount, amount: Amount, client: u16, tx: u32) -> Result<Amount, EngineError> {
+    balance.checked_add(amount).ok_or(EngineError::Overflow { client, tx, amount })
+}
+
+// checked subtract on a balance, in case it would go negative
+fn debit(balance: Amount, amount: Amount, client: u16, tx: u32) -> Result<Amount, EngineError> {
+    match balance.checked_sub(amount) {
+        Some(remaining) if !remaining.is_negative() => Ok(remaining),
+        _ => Err(EngineError::InsufficientFunds { client, tx, available: balance, requested: amount }),
+    }
 }
  
+// checked subtract on a balance that's allowed to go below zero, only an i64 overflow stops it
+fn overdraw(balance: Amount, amount: Amount, client: u16, tx: u32) -> Result<Amount, EngineError> {
+    balance.checked_sub(amount).ok_or(EngineError::Overflow { client, tx, amount })
+}
+
+// take disputed funds out of a balance, under NegativeAvailablePolicy::AllowUnderDispute it's allowed to go below zero
+fn dispute_debit(balance: Amount, amount: Amount, config: &EngineConfig, client: u16, tx: u32) -> Result<Amount, EngineError> {
+    match config.negative_available {
+        NegativeAvailablePolicy::Forbid => debit(balance, amount, client, tx),
+        NegativeAvailablePolicy::AllowUnderDispute => overdraw(balance, amount, client, tx),
+    }
+}
+
 // function to handle deposits
-fn handle_deposit(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    let amount = incoming_tx.amount.ok_or(format!("[ERROR]: Client id: {}, Transaction id: {} A deposit requires an amount. Discarding transaction.", incoming_tx.client, incoming_tx.tx))?;
-    // perform checked add on available balance, in case of overflow
-    client.available = client.available.checked_add(amount)
-        .ok_or(format!("[ERROR]: Deposit tx: {}, amount: {}, will cause an overflowed (u64::MAX/10e3) account balance for client: {}. Discarding transaction.", incoming_tx.tx, (amount as f64)/10000.0, incoming_tx.client))?;
-    // update total
-    client.total = client.available + client.held;
-    Ok(())
+fn handle_deposit(client: &mut Client, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    let available = credit(client.available, amount, client.client, incoming_tx.tx)?;
+    // update total, we only touch the client once every balance has been checked
+    client.total = credit(client.total, amount, client.client, incoming_tx.tx)?;
+    client.available = available;
+    record_transaction(transactions, incoming_tx, amount);
+    Ok(Outcome::Deposited(amount))
 }
 
 // function to handle withdrawals
-fn handle_withdrawal(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    let amount = incoming_tx.amount.ok_or(format!("[ERROR]: Client id: {}, Transaction id: {}, A withdrawal requires an amount. Discarding transaction.", incoming_tx.client, incoming_tx.tx))?;
-    // perform checked subtract on available balance, in case of overflow
-    client.available = client.available.checked_sub(amount)
-        .ok_or(format!("[ERROR]: Withdrawal tx: {}, amount: {}, will cause an underflowed (u64) or negative account balance for client: {}. Discarding transaction.", incoming_tx.tx, (amount as f64)/10000.0, incoming_tx.client))?;
-    // update total
-    client.total = client.available + client.held;
-    Ok(())
+fn handle_withdrawal(client: &mut Client, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    client.available = debit(client.available, amount, client.client, incoming_tx.tx)?;
+    // update total, held funds can't be withdrawn so this can't underflow once available has been checked
+    client.total = debit(client.total, amount, client.client, incoming_tx.tx)?;
+    record_transaction(transactions, incoming_tx, amount);
+    Ok(Outcome::Withdrawn(amount))
+}
+
+// function to handle adjustments, a positive amount is credited like a deposit and a negative one debited like a withdrawal
+// it's remembered so its tx id can't be reused, but it can't be disputed
+fn handle_adjustment(client: &mut Client, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    let (available, total) = match amount.is_negative() {
+        false => (credit(client.available, amount, client.client, incoming_tx.tx)?, credit(client.total, amount, client.client, incoming_tx.tx)?),
+        true => {
+            let taken = amount.checked_neg().ok_or(EngineError::Overflow { client: client.client, tx: incoming_tx.tx, amount })?;
+            (debit(client.available, taken, client.client, incoming_tx.tx)?, debit(client.total, taken, client.client, incoming_tx.tx)?)
+        },
+    };
+    client.available = available;
+    client.total = total;
+    record_transaction(transactions, incoming_tx, amount);
+    Ok(Outcome::Adjusted(amount))
+}
+
+// function to handle transfers, the sender can't overdraw and the recipient can't overflow, like a withdrawal followed by a deposit
+fn handle_transfer(clients: &mut HashMap<u16, Client>, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    let (client_id, tx) = (incoming_tx.client, incoming_tx.tx);
+    let to = incoming_tx.to.filter(|to| *to != client_id).ok_or(EngineError::InvalidDestination { client: client_id, tx })?;
+    let sender = get_or_insert(client_id, clients);
+    let sender_available = debit(sender.available, amount, client_id, tx)?;
+    let sender_total = debit(sender.total, amount, client_id, tx)?;
+    // a recipient we haven't heard of yet starts from nothing
+    let (recipient_available, recipient_total) = match clients.get(&to) {
+        Some(recipient) => {
+            recipient.is_frozen(tx)?;
+            (credit(recipient.available, amount, to, tx)?, credit(recipient.total, amount, to, tx)?)
+        },
+        None => (amount, amount),
+    };
+    // both sides have been checked, so now both of them move
+    let sender = get_or_insert(client_id, clients);
+    sender.available = sender_available;
+    sender.total = sender_total;
+    let recipient = get_or_insert(to, clients);
+    recipient.available = recipient_available;
+    recipient.total = recipient_total;
+    record_transaction(transactions, incoming_tx, amount);
+    Ok(Outcome::Transferred(amount))
+}
+
+// function to handle disputes, resolves and chargebacks of transfers
+// only the sender can dispute a transfer, and it's the recipient who holds the disputed funds, the same as if they had deposited them:
+// a dispute moves the amount from the recipient's available to held, a resolve moves it back,
+// and a chargeback returns it to the sender's available and locks the recipient's account
+fn handle_transfer_dispute(clients: &mut HashMap<u16, Client>, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction, event: DisputeEvent) -> Result<Outcome, EngineError> {
+    let (client_id, tx) = (incoming_tx.client, incoming_tx.tx);
+    let referenced_tx = find_referenced_transaction(incoming_tx, transactions)?;
+    let next_state = referenced_tx.next_state(event, config.redispute, tx)?;
+    let amount = referenced_tx.amount;
+    // a transfer is always to another client whose account was opened when it went through
+    let [Some(sender), Some(recipient)] = referenced_tx.to.map_or([None, None], |to| clients.get_disjoint_mut([&client_id, &to])) else {
+        return Err(EngineError::InvalidDestination { client: client_id, tx });
+    };
+    recipient.is_frozen(tx)?;
+    let outcome = match event {
+        DisputeEvent::Dispute => {
+            let available = dispute_debit(recipient.available, amount, config, recipient.client, tx)?;
+            recipient.held = credit(recipient.held, amount, recipient.client, tx)?;
+            recipient.available = available;
+            recipient.flagged |= available.is_negative();
+            Outcome::Disputed(amount)
+        },
+        DisputeEvent::Resolve => {
+            let held = debit(recipient.held, amount, recipient.client, tx)?;
+            recipient.available = credit(recipient.available, amount, recipient.client, tx)?;
+            recipient.held = held;
+            Outcome::Resolved(amount)
+        },
+        DisputeEvent::Chargeback => {
+            let held = debit(recipient.held, amount, recipient.client, tx)?;
+            let total = dispute_debit(recipient.total, amount, config, recipient.client, tx)?;
+            let sender_available = credit(sender.available, amount, client_id, tx)?;
+            sender.total = credit(sender.total, amount, client_id, tx)?;
+            sender.available = sender_available;
+            recipient.held = held;
+            recipient.total = total;
+            recipient.locked = true;
+            Outcome::ChargedBack(amount)
+        },
+    };
+    referenced_tx.state = next_state;
+    Ok(outcome)
+}
+
+// function to handle fees and interest, a fee is taken like a withdrawal (it can't overdraw) and interest is paid like a deposit
+// they're remembered so their tx ids can't be reused, and so a dispute of one can say it isn't disputable
+fn handle_posting(client: &mut Client, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    let (available, total, outcome) = match incoming_tx.transaction_type {
+        TransactionType::Fee => (debit(client.available, amount, client.client, incoming_tx.tx)?, debit(client.total, amount, client.client, incoming_tx.tx)?, Outcome::FeeCharged(amount)),
+        _ => (credit(client.available, amount, client.client, incoming_tx.tx)?, credit(client.total, amount, client.client, incoming_tx.tx)?, Outcome::InterestPaid(amount)),
+    };
+    client.available = available;
+    client.total = total;
+    record_transaction(transactions, incoming_tx, amount);
+    Ok(outcome)
+}
+
+// function to handle authorizations, the funds are held the same way a disputed deposit's are so the record starts out Disputed
+fn handle_authorize(client: &mut Client, transactions: &mut TransactionIndex, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let amount = required_amount(incoming_tx)?;
+    // an authorization can't overdraw, whatever NegativeAvailablePolicy says
+    let available = debit(client.available, amount, client.client, incoming_tx.tx)?;
+    client.held = credit(client.held, amount, client.client, incoming_tx.tx)?;
+    client.available = available;
+    record_transaction(transactions, incoming_tx, amount);
+    if let Some(record) = transactions.get_mut(&incoming_tx.tx) {
+        record.state = DisputeState::Disputed;
+    }
+    Ok(Outcome::Authorized(amount))
+}
+
+// find the authorization a capture or void refers to, anything else isn't one it can settle
+fn find_authorization<'a>(incoming_tx: &Transaction, transactions: &'a mut TransactionIndex) -> Result<&'a mut TransactionRecord, EngineError> {
+    match find_referenced_transaction(incoming_tx, transactions)? {
+        record if record.transaction_type == TransactionType::Authorize => Ok(record),
+        _ => Err(EngineError::UnknownTransaction { client: incoming_tx.client, tx: incoming_tx.tx }),
+    }
+}
+
+// function to handle captures, a capture without an amount takes the whole authorization
+// the captured amount leaves held and total like a chargeback, and whatever wasn't captured goes back to available
+fn handle_capture(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let client_id = client.client;
+    let authorization = find_authorization(incoming_tx, transactions)?;
+    // a voided or expired authorization has already been resolved, and a captured one charged back
+    let next_state = authorization.next_state(DisputeEvent::Chargeback, config.redispute, incoming_tx.tx)?;
+    let authorized = authorization.amount;
+    let captured = incoming_tx.amount.unwrap_or(authorized);
+    let Some(released) = authorized.checked_sub(captured).filter(|released| !released.is_negative()) else {
+        return Err(EngineError::ExceedsAuthorization { client: client_id, tx: incoming_tx.tx, authorized, requested: captured });
+    };
+    let held = debit(client.held, authorized, client_id, incoming_tx.tx)?;
+    let total = debit(client.total, captured, client_id, incoming_tx.tx)?;
+    let available = credit(client.available, released, client_id, incoming_tx.tx)?;
+    authorization.state = next_state;
+    client.held = held;
+    client.total = total;
+    client.available = available;
+    Ok(Outcome::Captured(captured))
+}
+
+// function to handle voids, the whole authorization goes back to available like a resolved dispute
+fn handle_void(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let authorization = find_authorization(incoming_tx, transactions)?;
+    release_authorization(client, authorization, config, incoming_tx.tx).map(Outcome::Voided)
+}
+
+// move an open authorization's funds from held back to available, returns the amount released
+fn release_authorization(client: &mut Client, authorization: &mut TransactionRecord, config: &EngineConfig, tx: u32) -> Result<Amount, EngineError> {
+    let next_state = authorization.next_state(DisputeEvent::Resolve, config.redispute, tx)?;
+    let amount = authorization.amount;
+    let held = debit(client.held, amount, client.client, tx)?;
+    client.available = credit(client.available, amount, client.client, tx)?;
+    client.held = held;
+    authorization.state = next_state;
+    Ok(amount)
+}
+
+// release the authorization with tx because it has expired, unless it has already been captured or voided
+// this happens on the engine's behalf rather than the client's, so it goes through even if the account is frozen
+// returns the amount released, None if there was nothing left to release
+pub fn expire_authorization(clients: &mut HashMap<u16, Client>, transactions: &mut TransactionIndex, config: &EngineConfig, tx: u32) -> Option<Amount> {
+    let authorization = transactions.get_mut(&tx).filter(|record| record.transaction_type == TransactionType::Authorize)?;
+    let client = clients.get_mut(&authorization.client)?;
+    release_authorization(client, authorization, config, tx).ok()
 }
 
 // function to handle disputes
-fn handle_dispute(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    let mut referenced_tx = find_referenced_transaction(&incoming_tx.tx, &mut client.transactions)?; 
-    // make sure referenced tx isn't already being disputed
-    referenced_tx.is_already_disputed()?;
-    // make sure referenced tx actually contains an amount
-    let amount = referenced_tx.amount
-        .ok_or(format!("[ERROR]: Disputed transaction: {} does not have an amount! Discarding transaction.", incoming_tx.tx))?;
+fn handle_dispute(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let client_id = client.client;
+    let referenced_tx = find_referenced_transaction(incoming_tx, transactions)?; 
+    // the client didn't make a fee or interest posting or an operator's adjustment so it isn't theirs to dispute, and an authorization is settled by capturing or voiding it
+    if matches!(referenced_tx.transaction_type, TransactionType::Fee | TransactionType::Interest | TransactionType::Adjustment | TransactionType::Authorize) {
+        return Err(EngineError::NotDisputable { client: client_id, tx: incoming_tx.tx });
+    }
+    // make sure referenced tx can be disputed right now
+    let next_state = referenced_tx.next_state(DisputeEvent::Dispute, config.redispute, incoming_tx.tx)?;
+    let amount = referenced_tx.amount;
     // I make an assumption that different logic is required to dispute a Deposit vs a Withdrawal
     match referenced_tx.transaction_type {
         TransactionType::Deposit => {
-            // available funds decrease, perform checked subtraction on available balance, in case of overflow
-            client.available = client.available.checked_sub(amount)
-                .ok_or(format!("[ERROR]: Dispute on tx: {}, for amount: {}, will cause a underflowed (u64) or negative available balance if disputed. Discarding transaction.", incoming_tx.tx, amount))?;
-            // held funds increase, perform checked add on held balance, in case of overflow
-            client.held = client.held.checked_add(amount)
-                .ok_or(format!("[ERROR]: Dispute on tx: {}, for amount: {}, will cause an overflowed (MAX::u64/10e3) held balance for client {}. Discarding transaction.", incoming_tx.tx, amount, client.client))?;
-            // at this point we know we have a valid dispute, so we can go ahead and change the disputed flag, on the referenced tx
-            referenced_tx.disputed = true;
+            // available funds decrease, held funds increase
+            let available = dispute_debit(client.available, amount, config, client_id, incoming_tx.tx)?;
+            let held = credit(client.held, amount, client_id, incoming_tx.tx)?;
+            // at this point we know we have a valid dispute, so we can go ahead and move the referenced tx along
+            referenced_tx.state = next_state;
+            client.available = available;
+            client.held = held;
+            client.flagged |= available.is_negative();
             // total funds remain the same
-            Ok(())
+            Ok(Outcome::Disputed(amount))
         },
-        TransactionType::Withdrawal => {
-            // no change to available funds when disputing a withdrawal
-            // held funds increase, perform checked add on held balance, in case of overflow
-            client.held = client.held.checked_add(amount)
-                .ok_or(format!("[ERROR]: Dispute on tx: {}, for amount: {}, will cause an overflowed (MAX::u64/10e3) held balance for client {}. Discarding transaction.", incoming_tx.tx, amount, client.client))?;
-            // at this point we know we have a valid dispute, so we can go ahead and change the disputed flag, on the referenced tx
-            referenced_tx.disputed = true;
-            // total funds have increased since we are giving a potential refund
-            client.total = client.available + client.held;
-            Ok(())
+        // what a withdrawal dispute does is up to the partner, see WithdrawalDisputePolicy
+        TransactionType::Withdrawal => match config.withdrawal_disputes {
+            WithdrawalDisputePolicy::Reject => Err(EngineError::NotDisputable { client: client_id, tx: incoming_tx.tx }),
+            WithdrawalDisputePolicy::HoldAsRefund => {
+                // no change to available funds when disputing a withdrawal, held funds increase
+                let held = credit(client.held, amount, client_id, incoming_tx.tx)?;
+                // total funds have increased since we are giving a potential refund
+                let total = credit(client.total, amount, client_id, incoming_tx.tx)?;
+                // at this point we know we have a valid dispute, so we can go ahead and move the referenced tx along
+                referenced_tx.state = next_state;
+                referenced_tx.dispute_policy = Some(WithdrawalDisputePolicy::HoldAsRefund);
+                client.held = held;
+                client.total = total;
+                Ok(Outcome::Disputed(amount))
+            },
+            WithdrawalDisputePolicy::ReverseImmediately => {
+                // the withdrawal is undone straight away, the funds are back in available (and total) while the dispute is open
+                let available = credit(client.available, amount, client_id, incoming_tx.tx)?;
+                let total = credit(client.total, amount, client_id, incoming_tx.tx)?;
+                referenced_tx.state = next_state;
+                referenced_tx.dispute_policy = Some(WithdrawalDisputePolicy::ReverseImmediately);
+                client.available = available;
+                client.total = total;
+                Ok(Outcome::Disputed(amount))
+            },
         },
-        _ => Err(format!("[ERROR]: Cannot dispute any transaction other than a Withdrawal or Deposit").into()),
+        // only deposits and withdrawals can be disputed
+        _ => Err(EngineError::UnknownTransaction { client: client_id, tx: incoming_tx.tx }),
     } 
 }
 
+// the WithdrawalDisputePolicy a withdrawal's dispute is settled under, the one it was opened under
+// (a snapshot written before we kept it doesn't have one, so those go by the config)
+fn settled_under(record: &TransactionRecord, config: &EngineConfig) -> WithdrawalDisputePolicy {
+    record.dispute_policy.unwrap_or(config.withdrawal_disputes)
+}
+
 // function to handle resolutions
-fn handle_resolve(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    // find transaction referenced by resolve
-    let referenced_tx = find_referenced_transaction(&incoming_tx.tx, &mut client.transactions)?;
-    // make sure transaction doesn't already have a resolve
-    referenced_tx.is_already_resolved()?;
-    // make sure referenced tx actually contains an amount
-    let amount = referenced_tx.amount
-        .ok_or(format!("[ERROR]: Resolve references a tx: {}, which does not have an amount! Discarding transaction.", incoming_tx.tx))?;
-    // check to see if transaction is disputed
-    match referenced_tx.disputed {
-        // referenced tx IS NOT disputed
-        false => Err(format!("[ERROR]: Resolve references a tx: {}, that isn't under dispute. Discarding transaction.", incoming_tx.tx).into()),
-        // referenced tx IS disputed
-        true => {
-            // held funds decrease, checked subraction on held balance, in case of overflow
-            client.held = client.held.checked_sub(amount)
-                .ok_or(format!("[ERROR]: Resolve on tx: {}, for amount: {}, will cause an underflow (u64) on held balance for client {}. Discarding transaction.", incoming_tx.tx, amount, client.client))?;
-            // available funds increase, checked addition on available balance, in case of overflow
-            client.available = client.available.checked_add(amount)
-                .ok_or(format!("[ERROR]: Resolve on tx: {}, for amount: {}, will cause an overflow (MAX::u64/10e3) on available balance for client {}. Discarding transaction.", incoming_tx.tx, amount, client.client))?;
-            Ok(())
-        } 
+fn handle_resolve(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let client_id = client.client;
+    // find transaction referenced by resolve, an authorization's held funds aren't a dispute's to release
+    let referenced_tx = find_referenced_transaction(incoming_tx, transactions)?;
+    if referenced_tx.transaction_type == TransactionType::Authorize {
+        return Err(EngineError::NotDisputed { client: client_id, tx: incoming_tx.tx });
+    }
+    // make sure transaction is under dispute and doesn't already have a resolve or chargeback
+    let next_state = referenced_tx.next_state(DisputeEvent::Resolve, config.redispute, incoming_tx.tx)?;
+    let amount = referenced_tx.amount;
+    // a reversed withdrawal was refunded when it was disputed, resolving the dispute just lets the refund stand
+    if referenced_tx.transaction_type == TransactionType::Withdrawal && settled_under(referenced_tx, config) == WithdrawalDisputePolicy::ReverseImmediately {
+        referenced_tx.state = next_state;
+        return Ok(Outcome::Resolved(amount));
     }
+    // held funds decrease, available funds increase
+    let held = debit(client.held, amount, client_id, incoming_tx.tx)?;
+    let available = credit(client.available, amount, client_id, incoming_tx.tx)?;
+    referenced_tx.state = next_state;
+    client.held = held;
+    client.available = available;
+    Ok(Outcome::Resolved(amount))
 }
 
 // function to handle chargebacks
-fn handle_chargeback(client: &mut Client, incoming_tx: &Transaction) -> Result<(), Box<dyn Error>> {
+fn handle_chargeback(client: &mut Client, transactions: &mut TransactionIndex, config: &EngineConfig, incoming_tx: &Transaction) -> Result<Outcome, EngineError> {
+    let client_id = client.client;
     // find transaction referenced by chargeback
-    let referenced_tx = find_referenced_transaction(&incoming_tx.tx, &mut client.transactions)?; 
-    // make sure referenced tx doesn't already have a chargeback
-    referenced_tx.is_already_resolved()?;
-    // make sure referenced tx actually contains an amount
-    let amount = referenced_tx.amount
-        .ok_or(format!("[ERROR]: Chargeback references tx: {}, which does not have an amount! Discarding transaction", incoming_tx.tx))?;
-    // check to see if transaction is disputed
-    match referenced_tx.disputed {
-        // referenced tx IS NOT disputed
-        false => Err(format!("[ERROR]: Chargeback for client: {}, references tx: {}, which isn't under dispute. Discarding transaction.", incoming_tx.client, incoming_tx.tx).into()),
-        // referenced tx IS disputed
-        true => {
-            // held funds decrease, checked subraction on held balance, in case of overflow
-            client.held = client.held.checked_sub(amount)
-                .ok_or(format!("[ERROR]: Chargeback on tx: {}, for amount: {}, will cause an underflow (u64) on the held balance for client {}. Discarding transaction.", incoming_tx.tx, amount, client.client))?;
-            // total funds decrease by the amount subtracted from held
-            client.total = client.available + client.held; 
-            // at this point we have a valid charge back and have performed the adjustments on the client's held and available funds
-            // freeze client's account
-            client.locked = true;
-            Ok(())
-        } 
+    let referenced_tx = find_referenced_transaction(incoming_tx, transactions)?; 
+    if referenced_tx.transaction_type == TransactionType::Authorize {
+        return Err(EngineError::NotDisputed { client: client_id, tx: incoming_tx.tx });
     }
-}
\ No newline at end of file
+    // make sure transaction is under dispute and doesn't already have a resolve or chargeback
+    let next_state = referenced_tx.next_state(DisputeEvent::Chargeback, config.redispute, incoming_tx.tx)?;
+    let amount = referenced_tx.amount;
+    match (referenced_tx.transaction_type, settled_under(referenced_tx, config)) {
+        // a reversed withdrawal's refund is taken back out of available funds, so the withdrawal stands after all
+        // the client may have spent the refund while the dispute was open, under NegativeAvailablePolicy::AllowUnderDispute it's taken back
+        // regardless and the account is flagged, otherwise the chargeback is refused until the funds are back
+        (TransactionType::Withdrawal, WithdrawalDisputePolicy::ReverseImmediately) => {
+            let available = dispute_debit(client.available, amount, config, client_id, incoming_tx.tx)?;
+            let total = dispute_debit(client.total, amount, config, client_id, incoming_tx.tx)?;
+            referenced_tx.state = next_state;
+            client.available = available;
+            client.total = total;
+            client.flagged |= available.is_negative();
+        },
+        // held funds decrease, total funds decrease by the amount subtracted from held
+        // total only goes negative if available already has, which the dispute has flagged
+        _ => {
+            let held = debit(client.held, amount, client_id, incoming_tx.tx)?;
+            let total = dispute_debit(client.total, amount, config, client_id, incoming_tx.tx)?;
+            referenced_tx.state = next_state;
+            client.held = held;
+            client.total = total;
+        },
+    }
+    // at this point we have a valid charge back and have performed the adjustments on the client's held and available funds
+    // freeze client's account
+    client.locked = true;
+    Ok(Outcome::ChargedBack(amount))
+}
@@ -0,0 +1,4 @@
+// the payments engine as a library, main.rs is a thin command line wrapper around this
+pub mod components;
+
+pub use components::data_structures::{Amount, AmountError, Client, RawClient, Transaction, RawTransaction, TXType, TransactionType, PaymentsEngine, Outcome, EngineError, TransactionRecord, TxIdSet, DisputeState, DisputeEvent, RedisputePolicy, EngineConfig, AccountOrder, OutputFormat, ProcessedRecord, RecordSink, RejectionReport, RejectedRecord, Strict, FraudLog, Trace, TraceRecord, AccountDifference, Tally, RawTransactionRecord, TransactionResponse, TxOwners, InputFormat, SnapshotHeader, JournalEntry, Journal, Statement, StatementRecord, StatementSection, AuditRecord, AuditReport, WithdrawalDisputePolicy, NegativeAvailablePolicy, FeeTier, FeeSchedule, FeeBatch};
@@ -1,30 +1,15 @@
 use std::process;
-use std::collections::HashMap;
-use std::error::Error;
-#[allow(unused_imports)]
-use std::fs::File;
 
 // LOCAL
-mod components;
-#[allow(unused_imports)]
-use components::utilities::{parse_args, process_transaction_data, output_accounts, generate_test_data};
-
-fn run_payments_engine() -> Result<(), Box<dyn Error>> {
-    //generate_test_data()?;
-    let input_filename = parse_args()?;
-    //File::create("transactions.log")?;
-    let mut client_data = HashMap::new();
-    process_transaction_data(&input_filename, &mut client_data)?;
-    output_accounts(&client_data)?;
-    Ok(())
-}
+use rust_coding_test::components::cli::{parse_args, run};
 
 fn main() {
-    match run_payments_engine() {
-        Ok(()) => (),
+    match parse_args().and_then(run) {
+        Ok(code) => process::exit(code),
         Err(err) => {
-            println!("{}", err.to_string());
+            // errors go to STDERR so they never end up mixed into the CSV on STDOUT
+            eprintln!("{}", err);
             process::exit(1)
         },
     }
-}
\ No newline at end of file
+}